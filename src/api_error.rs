@@ -0,0 +1,71 @@
+//! api_error.rs – errors returned by the HTTP API.
+//!
+//! Every handler returns `Result<_, ApiError>`; actix renders the error as
+//! `{"error": "<message>"}` with the matching status, so a bad request never
+//! takes a worker down.
+
+use actix_web::{http::{header, StatusCode}, HttpResponse, ResponseError};
+use serde::Serialize;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    #[error("invalid FEN {fen:?}: {reason}")]
+    BadFen { fen: String, reason: String },
+    #[error("illegal position {fen:?}: {reason}")]
+    IllegalPosition { fen: String, reason: String },
+    /// `ply` counts from 1 within the `play` or `pgn` parameter.
+    #[error("illegal move {mv:?} at ply {ply}: {reason}")]
+    IllegalMove { mv: String, ply: usize, reason: String },
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("unknown dataset {0:?}")]
+    UnknownDataset(String),
+    #[error("database unavailable: {0}")]
+    Unavailable(String),
+    #[error("request timed out")]
+    Timeout,
+    #[error("missing or unknown API key")]
+    Unauthorized,
+    #[error("rate limit exceeded, retry in {retry_after}s")]
+    RateLimited { retry_after: u64 },
+    #[error("internal error: {0}")]
+    Internal(String),
+}
+
+#[derive(Serialize)]
+struct Body {
+    error: String,
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::BadFen { .. }
+            | Self::IllegalPosition { .. }
+            | Self::IllegalMove { .. }
+            | Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Self::UnknownDataset(_) => StatusCode::NOT_FOUND,
+            Self::Unavailable(_) | Self::Timeout => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut res = HttpResponse::build(self.status_code());
+        if let Self::RateLimited { retry_after } = self {
+            res.insert_header((header::RETRY_AFTER, retry_after.to_string()));
+        }
+        res.json(Body { error: self.to_string() })
+    }
+}
+
+impl From<rocksdb::Error> for ApiError {
+    fn from(err: rocksdb::Error) -> Self { Self::Unavailable(err.to_string()) }
+}
+
+/// The blocking pool is gone, i.e. the server is shutting down.
+impl From<actix_web::error::BlockingError> for ApiError {
+    fn from(err: actix_web::error::BlockingError) -> Self { Self::Unavailable(err.to_string()) }
+}
@@ -0,0 +1,131 @@
+//! budget.rs – split one memory budget between the ingest pipeline and
+//! `RocksDB`.
+//!
+//! Worker caches used to be bounded by entry count, so their real footprint
+//! grew with the thread count.  Everything here is sized in bytes from a
+//! single figure: `resources.memory_budget`, or half of the memory the OS
+//! reports as available.  Fewer threads run when the budget cannot give each
+//! worker `MIN_CACHE_BYTES`, and a budget too small for even one is rejected.
+
+use anyhow::{ensure, Result as AnyResult};
+use crate::config;
+use sysinfo::{System, SystemExt};
+
+/// Rough size of a `GameSummary` waiting in the channel.
+const GAME_BYTES: usize = 1024;
+
+/// Smallest `StatsCache` worth flushing from.
+pub const MIN_CACHE_BYTES: usize = 1 << 20;
+
+/// Concrete sizes for one ingest run.
+#[derive(Clone, Copy, Debug)]
+pub struct Budget {
+    /// Worker threads in the Rayon pool.
+    pub threads: usize,
+    /// Games buffered between readers and workers.
+    pub channel_capacity: usize,
+    /// Bytes each worker's `StatsCache` may hold before flushing.
+    pub cache_bytes: usize,
+    /// Bytes each worker's `PrefixCache` may hold; `0` if it is disabled.
+    pub prefix_bytes: usize,
+    /// Bytes for the shared hot‑position table.
+    pub hot_bytes: usize,
+    /// Bytes for the fingerprints the run claims before they spill to disk.
+    pub seen_bytes: usize,
+    /// `RocksDB` block cache.
+    pub block_cache_bytes: usize,
+    /// Memtables, charged against the block cache.
+    pub write_buffer_bytes: usize,
+}
+
+impl Budget {
+    pub fn from_config(cfg: &config::Ingest) -> AnyResult<Self> {
+        let res = &cfg.resources;
+        let total = res.memory_budget.map_or_else(
+            || available_memory() / 2,
+            |b| usize::try_from(b).unwrap_or(usize::MAX),
+        );
+        let channel_capacity = res
+            .channel_capacity
+            .unwrap_or_else(|| std::cmp::max(4096, cfg.cache_size / 16))
+            .max(1);
+
+        // RocksDB gets a quarter, split evenly between reads and memtables;
+        // the channel is paid for next, the hot table takes an eighth of
+        // what is left, the run's fingerprints a sixteenth, the workers'
+        // prefix tries another sixteenth and their caches the rest.
+        let rocks = total / 4;
+        let channel = channel_capacity.saturating_mul(GAME_BYTES);
+        let rest = total.saturating_sub(rocks).saturating_sub(channel);
+        let hot = rest / 8;
+        let seen = rest / 16;
+        let prefixes = if cfg.prefix_plies > 0 { rest / 16 } else { 0 };
+        let caches = rest - hot - seen - prefixes;
+        ensure!(
+            caches >= MIN_CACHE_BYTES,
+            "memory budget of {total} bytes leaves {caches} for worker caches, below the minimum of \
+             {MIN_CACHE_BYTES}; raise memory_budget or lower channel_capacity",
+        );
+        let threads = res.threads.unwrap_or_else(num_cpus::get).clamp(1, caches / MIN_CACHE_BYTES);
+
+        Ok(Self {
+            threads,
+            channel_capacity,
+            cache_bytes: caches / threads,
+            prefix_bytes: prefixes / threads,
+            hot_bytes: hot,
+            seen_bytes: seen,
+            block_cache_bytes: rocks / 2,
+            write_buffer_bytes: rocks - rocks / 2,
+        })
+    }
+}
+
+fn available_memory() -> usize {
+    let mut sys = System::new();
+    sys.refresh_memory();
+    usize::try_from(sys.available_memory()).unwrap_or(usize::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ingest(resources: serde_json::Value) -> config::Ingest {
+        config::test_ingest(serde_json::json!({ "resources": resources }))
+    }
+
+    #[test]
+    fn budget_is_split_without_overcommitting() {
+        let gib = 1u64 << 30;
+        let b = Budget::from_config(&ingest(serde_json::json!({
+            "memory_budget": gib, "threads": 4, "channel_capacity": 1000,
+        }))).unwrap();
+        assert_eq!((b.threads, b.channel_capacity), (4, 1000));
+        assert_eq!(b.block_cache_bytes + b.write_buffer_bytes, (gib / 4) as usize);
+        let spent = b.block_cache_bytes + b.write_buffer_bytes
+            + b.channel_capacity * GAME_BYTES + b.hot_bytes + b.seen_bytes
+            + (b.cache_bytes + b.prefix_bytes) * b.threads;
+        assert_eq!(spent, gib as usize);
+        assert_eq!(b.prefix_bytes * 4, b.seen_bytes);
+
+        // Without prefix caching its share goes to the caches.
+        let off = Budget::from_config(&config::test_ingest(serde_json::json!({
+            "prefix_plies": 0, "resources": { "memory_budget": gib, "threads": 4, "channel_capacity": 1000 },
+        }))).unwrap();
+        assert_eq!((off.prefix_bytes, off.cache_bytes), (0, b.cache_bytes + b.prefix_bytes));
+
+        // Small budgets run fewer workers rather than overcommit …
+        let small = Budget::from_config(&ingest(serde_json::json!({
+            "memory_budget": 8u64 << 20, "threads": 8, "channel_capacity": 1000,
+        }))).unwrap();
+        assert_eq!((small.threads, small.cache_bytes >= MIN_CACHE_BYTES), (3, true));
+        assert!(small.cache_bytes * small.threads <= 8 << 20);
+
+        // … and tiny ones, here mostly spent on the default channel, fail.
+        let tiny = Budget::from_config(&ingest(serde_json::json!({
+            "memory_budget": 1u64 << 20, "threads": 8,
+        })));
+        assert!(tiny.unwrap_err().to_string().contains("minimum"));
+    }
+}
@@ -0,0 +1,280 @@
+//! bulk.rs – offline SST generation for first‑time builds.
+//!
+//! In bulk mode workers do not write merge operands into `RocksDB`.  Each
+//! flush is sorted and spilled to a run file; when every game has been read
+//! the runs are k‑way merged (in passes if there are too many to open at
+//! once), summed per key and written as SST files that
+//! `ingest_external_file` links into the database without going through
+//! memtables or compaction.  Keys are written as merge operands, so loading
+//! into a non‑empty database still adds to the existing counters.  Runs sort
+//! by dataset first, so each SST file belongs to one column family.
+
+use crate::chess_db::Key;
+use crate::dataset::Datasets;
+use crate::game_stats::GameWins;
+use anyhow::{Context, Result as AnyResult};
+use rocksdb::{IngestExternalFileOptions, Options, SstFileWriter, DB};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Roll over to a new SST file after this many bytes.
+const SST_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Runs merged at once.  Each is an open file, so this stays well below
+/// the usual limit of 1024 descriptors; more runs are merged in passes.
+const FAN_IN: usize = 256;
+
+/// Sorted runs spilled by the workers of one bulk load.
+pub struct RunWriter {
+    dir: PathBuf,
+    next: AtomicUsize,
+    runs: Mutex<Vec<PathBuf>>,
+    fan_in: usize,
+}
+
+impl RunWriter {
+    /// Spill files go to `dir`, which is created if missing.
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir, next: AtomicUsize::new(0), runs: Mutex::new(Vec::new()), fan_in: FAN_IN })
+    }
+
+    /// Merge at most `fan_in` runs at once instead of `FAN_IN`.
+    #[must_use] pub fn with_fan_in(self, fan_in: usize) -> Self {
+        Self { fan_in: fan_in.max(2), ..self }
+    }
+
+    /// Sort `entries` by key and write them as one run.
+    ///
+    /// Record layout: dataset (1 byte), key length (1 byte), key, 12‑byte
+    /// `GameWins`.
+    pub fn spill(&self, mut entries: Vec<(Key, GameWins)>) -> io::Result<()> {
+        if entries.is_empty() { return Ok(()); }
+        entries.sort_unstable_by_key(|e| e.0);
+
+        let path = self.run_path();
+        let mut w = BufWriter::new(fs::File::create(&path)?);
+        for (k, v) in entries { write_record(&mut w, k, v)?; }
+        w.flush()?;
+        self.runs.lock().expect("run list poisoned").push(path);
+        Ok(())
+    }
+
+    fn run_path(&self) -> PathBuf {
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        self.dir.join(format!("run-{n:06}.bin"))
+    }
+
+    /// Merge every run into SST files inside the spill directory, returned
+    /// with the dataset they belong to.
+    ///
+    /// `opts` must be the options the database is opened with, so the SST
+    /// files carry the same comparator, prefix extractor and merge operator.
+    pub fn into_ssts(self, opts: &Options) -> AnyResult<Vec<(u8, PathBuf)>> {
+        let mut runs = std::mem::take(&mut *self.runs.lock().expect("run list poisoned"));
+
+        // Too many to open at once: merge groups of them into longer runs.
+        while runs.len() > self.fan_in {
+            let mut merged = Vec::new();
+            for group in runs.chunks(self.fan_in) {
+                if let [run] = group { merged.push(run.clone()); continue; }
+                let path = self.run_path();
+                let mut w = BufWriter::new(fs::File::create(&path)?);
+                merge_runs(group, |k, v| Ok(write_record(&mut w, k, v)?))?;
+                w.flush()?;
+                for run in group { fs::remove_file(run)?; }
+                merged.push(path);
+            }
+            runs = merged;
+        }
+
+        let mut ssts = Vec::new();
+        let mut writer: Option<SstFileWriter> = None;
+        merge_runs(&runs, |key, total| {
+            // A new dataset starts a new file.
+            if ssts.last().is_some_and(|(ds, _)| *ds != key.dataset()) {
+                if let Some(mut w) = writer.take() { w.finish()?; }
+            }
+            if writer.is_none() {
+                let path = self.dir.join(format!("load-{:06}.sst", ssts.len()));
+                let w = SstFileWriter::create(opts);
+                w.open(&path).with_context(|| format!("open {path:?}"))?;
+                ssts.push((key.dataset(), path));
+                writer = Some(w);
+            }
+            let w = writer.as_mut().expect("SST writer just opened");
+            w.merge(key.as_bytes(), total.to_bytes())?;
+            if w.file_size() >= SST_BYTES {
+                w.finish()?;
+                writer = None;
+            }
+            Ok(())
+        })?;
+        if let Some(mut w) = writer { w.finish()?; }
+
+        for run in runs { fs::remove_file(run)?; }
+        Ok(ssts)
+    }
+
+    #[must_use] pub fn dir(&self) -> &Path { &self.dir }
+}
+
+/// K‑way merge of `runs`, passing each key once with its summed total to
+/// `emit` in key order.
+fn merge_runs(
+    runs: &[PathBuf],
+    mut emit: impl FnMut(Key, GameWins) -> AnyResult<()>,
+) -> AnyResult<()> {
+    let mut readers = runs
+        .iter()
+        .map(|p| Ok(BufReader::new(fs::File::open(p)?)))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    // Heap of (next key, run index); each run's pending value sits in
+    // `heads` so only keys need ordering.
+    let mut heads = vec![GameWins::new(); readers.len()];
+    let mut heap = BinaryHeap::new();
+    for (i, r) in readers.iter_mut().enumerate() {
+        if let Some((k, v)) = read_record(r)? {
+            heads[i] = v;
+            heap.push(Reverse((k, i)));
+        }
+    }
+
+    while let Some(Reverse((key, first))) = heap.pop() {
+        // Fold every run's entry for the same key.
+        let mut total = GameWins::new();
+        let mut i = first;
+        loop {
+            total = total.combine(&heads[i]);
+            if let Some((k, v)) = read_record(&mut readers[i])? {
+                heads[i] = v;
+                heap.push(Reverse((k, i)));
+            }
+            match heap.peek() {
+                Some(Reverse((k, j))) if *k == key => i = *j,
+                _ => break,
+            }
+            heap.pop();
+        }
+        emit(key, total)?;
+    }
+    Ok(())
+}
+
+/// Move `ssts` into their datasets and compact once everything is in place,
+/// then turn automatic compaction back on for later writes to the still open
+/// database (`for_bulk_load` turned it off).
+pub fn load(db: &DB, datasets: &Datasets, ssts: &[(u8, PathBuf)]) -> AnyResult<()> {
+    let mut opts = IngestExternalFileOptions::default();
+    opts.set_move_files(true);
+    for (ds, cf) in datasets.cfs(db).into_iter().enumerate() {
+        let files: Vec<_> = ssts.iter()
+            .filter(|(d, _)| usize::from(*d) == ds)
+            .map(|(_, p)| p.clone())
+            .collect();
+        if !files.is_empty() {
+            db.ingest_external_file_cf_opts(cf, &opts, files)
+                .with_context(|| format!("ingest SST files into {:?}", datasets.names()[ds]))?;
+        }
+        db.compact_range_cf(cf, None::<&[u8]>, None::<&[u8]>);
+        db.set_options_cf(cf, &[("disable_auto_compactions", "false")])
+            .with_context(|| format!("re-enable compactions of {:?}", datasets.names()[ds]))?;
+    }
+    Ok(())
+}
+
+fn write_record<W: Write>(w: &mut W, k: Key, v: GameWins) -> io::Result<()> {
+    let key = k.as_bytes();
+    w.write_all(&[k.dataset(), key.len() as u8])?;
+    w.write_all(key)?;
+    w.write_all(&v.to_bytes())
+}
+
+fn read_record<R: Read>(r: &mut R) -> io::Result<Option<(Key, GameWins)>> {
+    let mut head = [0u8; 2];
+    match r.read_exact(&mut head) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let [dataset, len] = head;
+    let mut buf = [0u8; 12 + crate::chess_db::MAX_KEY_LEN];
+    let n = usize::from(len);
+    r.read_exact(&mut buf[..n + 12])?;
+    let key = Key::from_slice(&buf[..n]).in_dataset(dataset);
+    Ok(Some((key, GameWins::from_bytes(&buf[n..n + 12]))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merge::wins_merge_op;
+    use crate::rocks_cfg;
+
+    #[test]
+    fn runs_merge_into_one_total_per_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut opts = rocks_cfg::tuned();
+        opts.set_merge_operator_associative("add_wins", wins_merge_op);
+        let wins = |white, black, draws| GameWins { black, white, draws };
+        let a = Key::from_slice(b"psaaaaaaaa");
+        let b = Key::from_slice(b"psbbbbbbbb");
+        let m = Key::from_slice(b"pmsaaaaaaaae2e4");
+
+        let runs = RunWriter::new(dir.path().join("spill")).unwrap();
+        runs.spill(vec![(b, wins(1, 0, 0)), (a, wins(0, 1, 0)), (m.in_dataset(1), wins(0, 0, 1))]).unwrap();
+        runs.spill(vec![(a, wins(2, 0, 0)), (m, wins(0, 0, 3))]).unwrap();
+        runs.spill(vec![(m.in_dataset(1), wins(1, 0, 0)), (a, wins(0, 0, 4))]).unwrap();
+        let ssts = runs.into_ssts(&opts).unwrap();
+        assert_eq!(ssts.iter().map(|(ds, _)| *ds).collect::<Vec<_>>(), [0, 1]);
+        assert!(!dir.path().join("spill/run-000000.bin").exists());
+
+        let totals = |ds: u8| {
+            let db = DB::open(&opts, dir.path().join(format!("db{ds}"))).unwrap();
+            let files: Vec<_> = ssts.iter().filter(|(d, _)| *d == ds).map(|(_, p)| p.clone()).collect();
+            db.ingest_external_file(files).unwrap();
+            [a, b, m].map(|k| db.get(k.as_bytes()).unwrap().map(|v| {
+                let w = GameWins::from_bytes(&v);
+                (w.white, w.black, w.draws)
+            }))
+        };
+        assert_eq!(totals(0), [Some((2, 1, 4)), Some((1, 0, 0)), Some((0, 0, 3))]);
+        assert_eq!(totals(1), [None, None, Some((1, 0, 1))]);
+    }
+
+    #[test]
+    fn more_runs_than_the_fan_in_merge_in_passes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut opts = rocks_cfg::tuned();
+        opts.set_merge_operator_associative("add_wins", wins_merge_op);
+        let shared = Key::from_slice(b"psaaaaaaaa");
+
+        let runs = RunWriter::new(dir.path().join("spill")).unwrap().with_fan_in(3);
+        for i in 0..10u32 {
+            let own = Key::from_slice(format!("ps{i:08}").as_bytes());
+            let wins = |white, black| GameWins { black, white, draws: 0 };
+            runs.spill(vec![(shared, wins(1, 0)), (own, wins(0, i))]).unwrap();
+        }
+        let ssts = runs.into_ssts(&opts).unwrap();
+        let left: Vec<_> = fs::read_dir(dir.path().join("spill")).unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with("run-"))
+            .collect();
+        assert!(left.is_empty(), "{left:?}");
+
+        let db = DB::open(&opts, dir.path().join("db")).unwrap();
+        db.ingest_external_file(ssts.into_iter().map(|(_, p)| p).collect()).unwrap();
+        let get = |k: &[u8]| db.get(k).unwrap().map(|v| GameWins::from_bytes(&v));
+        assert_eq!(get(shared.as_bytes()).map(|w| w.white), Some(10));
+        for i in 0..10u32 {
+            assert_eq!(get(format!("ps{i:08}").as_bytes()).map(|w| w.black), Some(i));
+        }
+    }
+}
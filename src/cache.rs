@@ -0,0 +1,138 @@
+//! cache.rs – in‑process cache of rendered API responses.
+//!
+//! Opening positions are asked for over and over, and each answer costs a
+//! `RocksDB` prefix scan plus SAN rendering.  Responses are kept as
+//! serialized JSON, keyed by endpoint, normalized position and options, and
+//! dropped wholesale when the ingest generation changes.  The entity tag of
+//! a response is a digest of its body, so it changes exactly when the answer
+//! does, and a cached answer serves `If-None-Match` without touching the
+//! database.
+
+use actix_web::web::Bytes;
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A rendered response.
+#[derive(Clone)]
+pub struct Cached {
+    pub body: Bytes,
+    /// Quoted, ready for the `ETag` header.
+    pub etag: String,
+}
+
+pub struct ResponseCache {
+    inner: Option<Mutex<Inner>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Inner {
+    generation: u64,
+    entries: LruCache<String, Cached>,
+}
+
+impl ResponseCache {
+    /// Keeps up to `capacity` responses; `0` caches nothing.
+    #[must_use] pub fn new(capacity: usize) -> Self {
+        let inner = NonZeroUsize::new(capacity).map(|cap| {
+            Mutex::new(Inner { generation: 0, entries: LruCache::new(cap) })
+        });
+        Self { inner, hits: AtomicU64::new(0), misses: AtomicU64::new(0) }
+    }
+
+    /// The response stored under `key` for `generation`.
+    pub fn get(&self, key: &str, generation: u64) -> Option<Cached> {
+        let mut inner = self.inner.as_ref()?.lock().expect("response cache poisoned");
+        inner.sync(generation);
+        let hit = inner.entries.get(key).cloned();
+        let counter = if hit.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        hit
+    }
+
+    /// Lookups answered from the cache and not, and responses held.
+    #[must_use] pub fn stats(&self) -> (u64, u64, usize) {
+        let len = self.inner.as_ref()
+            .map_or(0, |inner| inner.lock().expect("response cache poisoned").entries.len());
+        (self.hits.load(Ordering::Relaxed), self.misses.load(Ordering::Relaxed), len)
+    }
+
+    /// Store `body` under `key` for `generation` and return it with its tag.
+    pub fn insert(&self, key: String, generation: u64, body: Bytes) -> Cached {
+        let cached = Cached { etag: etag(&body), body };
+        if let Some(inner) = &self.inner {
+            let mut inner = inner.lock().expect("response cache poisoned");
+            inner.sync(generation);
+            // An answer computed before a catch‑up must not outlive it.
+            if inner.generation == generation {
+                inner.entries.put(key, cached.clone());
+            }
+        }
+        cached
+    }
+}
+
+impl Inner {
+    /// Forget everything once a newer generation is served.
+    fn sync(&mut self, generation: u64) {
+        if generation > self.generation {
+            self.entries.clear();
+            self.generation = generation;
+        }
+    }
+}
+
+/// Strong tag of a response with `body`.
+#[must_use] pub fn etag(body: &[u8]) -> String {
+    let hex: String = blake3::hash(body).as_bytes()[..12].iter().map(|b| format!("{b:02x}")).collect();
+    format!("\"{hex}\"")
+}
+
+/// Whether an `If-None-Match` header value matches `etag`.
+#[must_use] pub fn matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn newer_generations_drop_older_answers() {
+        let cache = ResponseCache::new(10);
+        assert!(cache.get("/a", 1).is_none());
+        let stored = cache.insert("/a".into(), 1, Bytes::from_static(b"{}"));
+        assert_eq!(cache.get("/a", 1).unwrap().etag, stored.etag);
+
+        // The first lookup after a catch‑up empties the cache.
+        assert!(cache.get("/a", 2).is_none());
+        assert_eq!(cache.stats(), (1, 2, 0));
+
+        // An answer rendered before the catch‑up is returned, not kept.
+        let late = cache.insert("/a".into(), 1, Bytes::from_static(b"{}"));
+        assert_eq!(late.body, Bytes::from_static(b"{}"));
+        assert!(cache.get("/a", 2).is_none());
+
+        let off = ResponseCache::new(0);
+        off.insert("/a".into(), 1, Bytes::from_static(b"{}"));
+        assert!(off.get("/a", 1).is_none());
+    }
+
+    #[test]
+    fn tags_follow_the_body() {
+        assert_eq!(etag(b"{\"white\":1}"), etag(b"{\"white\":1}"));
+        assert_ne!(etag(b"{\"white\":1}"), etag(b"{\"white\":2}"));
+
+        let tag = etag(b"{}");
+        assert!(tag.starts_with('"') && tag.ends_with('"'));
+        assert!(matches(&tag, &tag));
+        assert!(matches(&format!("\"other\", W/{tag}"), &tag));
+        assert!(matches("*", &tag));
+        assert!(!matches("\"other\"", &tag));
+        assert!(!matches("", &tag));
+    }
+}
@@ -1,5 +1,6 @@
 use crate::game_stats::{GameStats, GameWins};
-use rocksdb::{WriteBatch, DB};
+use rocksdb::{ColumnFamily, DBRawIterator, Range, WriteBatch, DB};
+use serde::Serialize;
 use shakmaty::{
     uci::Uci,
     CastlingMode,
@@ -9,6 +10,7 @@ use shakmaty::{
     zobrist::{Zobrist64, ZobristHash},
 };
 use std::collections::HashMap;
+use std::io::Write;
 
 //position stats
 const PS: &[u8] = b"ps";
@@ -16,6 +18,66 @@ const PS: &[u8] = b"ps";
 const PMS: &[u8] = b"pms";
 //file ingestion stats
 pub const FS: &[u8] = b"fs";
+//game fingerprints seen by ingest
+pub const GS: &[u8] = b"gs";
+//fingerprints claimed by a running ingest, until its counts are on disk
+pub const GP: &[u8] = b"gp";
+//completed ingest runs, in the default dataset
+const GENERATION: &[u8] = b"generation";
+//`SCHEMA_VERSION` of the last ingest run, in the default dataset
+const SCHEMA: &[u8] = b"schema";
+//generation an unfinished ingest run is writing, in the default dataset
+const INGESTING: &[u8] = b"ingesting";
+//filters of the last ingest into a dataset
+pub const SETTINGS: &[u8] = b"settings";
+
+/// Layout of keys and values written by ingest; bump it when either
+/// changes.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Longest key written by ingest: `pms` + 8‑byte hash + 5‑char UCI.
+pub const MAX_KEY_LEN: usize = 16;
+
+/// Fixed‑size, allocation‑free form of a `ps` / `pms` key, tagged with the
+/// index of the dataset it is written to (not part of the bytes).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Key {
+    dataset: u8,
+    len: u8,
+    buf: [u8; MAX_KEY_LEN],
+}
+
+impl Key {
+    fn from_parts(parts: &[&[u8]]) -> Self {
+        let mut key = Self { dataset: 0, len: 0, buf: [0; MAX_KEY_LEN] };
+        for part in parts {
+            let start = key.len as usize;
+            key.buf[start..start + part.len()].copy_from_slice(part);
+            key.len += part.len() as u8;
+        }
+        key
+    }
+
+    /// Panics if `bytes` is longer than `MAX_KEY_LEN`.
+    #[must_use] pub fn from_slice(bytes: &[u8]) -> Self { Self::from_parts(&[bytes]) }
+
+    #[must_use] pub fn as_bytes(&self) -> &[u8] { &self.buf[..self.len as usize] }
+
+    #[must_use] pub const fn in_dataset(self, dataset: u8) -> Self { Self { dataset, ..self } }
+
+    #[must_use] pub const fn dataset(&self) -> u8 { self.dataset }
+}
+
+/// Keys order by dataset, then like the `RocksDB` byte comparator.
+impl Ord for Key {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.dataset, self.as_bytes()).cmp(&(other.dataset, other.as_bytes()))
+    }
+}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> { Some(self.cmp(other)) }
+}
 
 #[must_use]
 pub fn pos_to_keyable(pos: &Chess) -> Vec<u8> {
@@ -26,9 +88,11 @@ pub fn pos_to_keyable(pos: &Chess) -> Vec<u8> {
 }
 
 #[must_use] pub fn pos_to_key(keyable: &[u8]) -> Vec<u8> {
-    let mut ret = PS.to_owned();
-    ret.append(&mut keyable.to_owned());
-    ret
+    pos_key(keyable).as_bytes().to_vec()
+}
+
+#[must_use] pub fn pos_key(keyable: &[u8]) -> Key {
+    Key::from_parts(&[PS, keyable])
 }
 
 #[must_use] pub fn pos_to_prefix(keyable: &[u8]) -> Vec<u8> {
@@ -38,99 +102,188 @@ pub fn pos_to_keyable(pos: &Chess) -> Vec<u8> {
 }
 
 #[must_use] pub fn pos_move_to_key(keyable: &[u8], chess_move: &Move) -> Vec<u8> {
-    let mut ret = pos_to_prefix(keyable);
-    ret.append(
-        &mut chess_move
-            .to_uci(CastlingMode::Standard)
-            .to_string()
-            .as_bytes()
-            .to_vec(),
-    );
-    ret
+    pos_move_key(keyable, chess_move).as_bytes().to_vec()
+}
+
+#[must_use] pub fn pos_move_key(keyable: &[u8], chess_move: &Move) -> Key {
+    let mut key = Key::from_parts(&[PMS, keyable]);
+    let uci = chess_move.to_uci(CastlingMode::Standard);
+    let written = {
+        let mut rest = &mut key.buf[key.len as usize..];
+        let before = rest.len();
+        write!(rest, "{uci}").expect("UCI longer than 5 chars");
+        before - rest.len()
+    };
+    key.len += written as u8;
+    key
+}
+
+
+/// A stored move that is not legal in `pos` belongs to another position with
+/// the same hash; it is skipped rather than failing the whole lookup.
+fn legal_uci(pos: &Chess, uci: &[u8]) -> Option<String> {
+    let m = Uci::from_ascii(uci).ok()?.to_move(pos).ok()?;
+    Some(m.to_uci(CastlingMode::Standard).to_string())
+}
+
+/// Number of ingest runs completed against `db`; `0` for a fresh database.
+#[must_use] pub fn generation(db: &DB) -> u64 {
+    db.get(GENERATION)
+        .ok()
+        .flatten()
+        .and_then(|v| <[u8; 8]>::try_from(v.as_slice()).ok())
+        .map_or(0, u64::from_be_bytes)
 }
 
-fn key_to_uci(key: &[u8], prefix: &[u8]) -> Uci {
-    Uci::from_ascii(&key[prefix.len()..]).expect("Failed to parse UCI from key")
+/// Mark an ingest run as started, before it writes any counts.  Readers
+/// that see the mark may see part of the run under the old generation; it
+/// stays after a crash, which leaves the database in just that state.
+pub fn begin_generation(db: &DB) -> Result<(), rocksdb::Error> {
+    db.put(INGESTING, (generation(db) + 1).to_be_bytes())
 }
 
-fn is_valid_prefix(key: &[u8], prefix: &[u8]) -> bool {
-    prefix
-        == key
-            .get(..prefix.len())
-            .expect("Prefix is longer than key!!")
+/// Whether an ingest run has started and not finished, see
+/// `begin_generation`.
+#[must_use] pub fn ingesting(db: &DB) -> bool {
+    db.get_pinned(INGESTING).ok().flatten().is_some()
+}
+
+/// Record one more completed ingest run, with the schema it wrote, and
+/// return its generation.
+pub fn bump_generation(db: &DB) -> Result<u64, rocksdb::Error> {
+    let next = generation(db) + 1;
+    let mut batch = WriteBatch::default();
+    batch.put(GENERATION, next.to_be_bytes());
+    batch.put(SCHEMA, SCHEMA_VERSION.to_be_bytes());
+    batch.delete(INGESTING);
+    db.write(batch)?;
+    Ok(next)
+}
+
+/// `SCHEMA_VERSION` the last ingest wrote; `None` for databases from
+/// before it was recorded.
+#[must_use] pub fn schema_version(db: &DB) -> Option<u32> {
+    let v = db.get(SCHEMA).ok()??;
+    Some(u32::from_be_bytes(v.as_slice().try_into().ok()?))
+}
+
+/// Rough key counts of a dataset: `RocksDB`'s key estimate, split between
+/// the key families by their share of the SST bytes.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct Estimate {
+    pub keys: u64,
+    pub positions: u64,
+    pub moves: u64,
+}
+
+pub fn estimate(db: &DB, cf: &ColumnFamily) -> Result<Estimate, rocksdb::Error> {
+    let keys = db.property_int_value_cf(cf, "rocksdb.estimate-num-keys")?.unwrap_or(0);
+    let families = [PS, PMS, GS];
+    let ends = families.map(successor);
+    let ranges: Vec<Range> = families.iter()
+        .zip(&ends)
+        .map(|(start, end)| Range::new(start, end))
+        .collect();
+    let sizes = db.get_approximate_sizes_cf(cf, &ranges);
+    let total: u64 = sizes.iter().sum();
+    if total == 0 { return Ok(Estimate { keys, ..Estimate::default() }); }
+    let share = |bytes: u64| (u128::from(keys) * u128::from(bytes) / u128::from(total)) as u64;
+    Ok(Estimate { keys, positions: share(sizes[0]), moves: share(sizes[1]) })
+}
+
+/// First key after every key starting with `prefix`.
+fn successor(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    *end.last_mut().expect("empty key prefix") += 1;
+    end
 }
 
 pub struct ChessDB<'a> {
     db: &'a DB,
-    cache: HashMap<Vec<u8>, GameWins>,
+    /// Dataset read.
+    cf: &'a ColumnFamily,
 }
 
-impl ChessDB<'_> {
-    #[must_use] pub fn new(db: &DB) -> ChessDB {
-        ChessDB {
-            db,
-            cache: HashMap::new(),
-        }
+impl<'a> ChessDB<'a> {
+    #[must_use] pub fn new(db: &'a DB, cf: &'a ColumnFamily) -> Self {
+        ChessDB { db, cf }
     }
 
-    pub fn get_pos_stats(&mut self, pos: &Chess) -> Option<GameStats> {
+    /// Stats of `pos`, or `None` if no ingested game reached it.
+    pub fn get_pos_stats(&self, pos: &Chess) -> Result<Option<GameStats>, rocksdb::Error> {
         let keyable = pos_to_keyable(pos);
         let prefix = pos_to_prefix(&keyable);
-        let prefix_iter = self.db.prefix_iterator(&prefix);
+        let prefix_iter = self.db.prefix_iterator_cf(self.cf, &prefix);
         let mut game_moves = HashMap::new();
         for item in prefix_iter {
-            let (key, value) = item.expect("Prefix iter error in rocks db?");
-            // NOTE: stopping iter on mismatched prefix, not sure how to bound it otherwise
-            if !is_valid_prefix(&key, &prefix) {
-                break;
+            let (key, value) = item?;
+            // The fixed-length extractor only bounds the first 11 bytes;
+            // a shorter key from the next family may follow.
+            if !key.starts_with(&prefix) { break; }
+            if let Some(uci) = legal_uci(pos, &key[prefix.len()..]) {
+                game_moves.insert(uci, GameWins::from_bytes(&value));
             }
-            let m = key_to_uci(&key, &prefix)
-                .to_move(pos)
-                .expect("The move is invalid uci for the position!");
-            let game_wins = GameWins::from_bytes(&value);
-            let uci = m.to_uci(CastlingMode::Standard).to_string();
-            game_moves.insert(uci, game_wins);
         }
 
-        Self::get_pos_wins(self, &keyable).map(|game_wins| GameStats {
+        Ok(self.get_pos_wins(&keyable)?.map(|game_wins| GameStats {
             game_wins,
             game_moves,
-        })
+        }))
     }
 
-    pub fn get_pos_wins(&mut self, keyable: &[u8]) -> Option<GameWins> {
-        let key = pos_to_key(keyable);
-        match self.cache.get(&key) {
-            None => {
-                let game_wins =
-                    GameWins::from_bytes(&self.db.get(&key).ok()??);
-                self.cache.insert(key, game_wins);
-                Some(game_wins)
-            }
-            game_wins => game_wins.copied(),
+    /// `get_pos_stats` of many positions: one `multi_get` for the totals,
+    /// and one iterator seeked through the move prefixes in key order.
+    pub fn get_many_pos_stats(&self, positions: &[Chess]) -> Result<Vec<Option<GameStats>>, rocksdb::Error> {
+        let keyables: Vec<Vec<u8>> = positions.iter().map(pos_to_keyable).collect();
+        let totals = self.db.multi_get_cf(keyables.iter().map(|k| (self.cf, pos_to_key(k))));
+
+        let mut order: Vec<usize> = (0..positions.len()).collect();
+        order.sort_by(|&a, &b| keyables[a].cmp(&keyables[b]));
+        let mut moves = vec![HashMap::new(); positions.len()];
+        let mut iter = self.db.raw_iterator_cf(self.cf);
+        for i in order {
+            moves[i] = self.moves_at(&mut iter, &positions[i], &keyables[i])?;
         }
+
+        totals.into_iter()
+            .zip(moves)
+            .map(|(total, game_moves)| Ok(total?.map(|bytes| GameStats {
+                game_wins: GameWins::from_bytes(&bytes),
+                game_moves,
+            })))
+            .collect()
     }
 
-    pub fn flush(&mut self) {
-        println!("Cache size at flush: {}", self.cache.len());
-        println!("Creating batch");
-        let mut batch = WriteBatch::default();
-        println!("Iterating the cache");
-        for (k, v) in self.cache.drain() {
-            batch.put(k, v.to_bytes());
+    fn moves_at(
+        &self,
+        iter: &mut DBRawIterator<'_>,
+        pos: &Chess,
+        keyable: &[u8],
+    ) -> Result<HashMap<String, GameWins>, rocksdb::Error> {
+        let prefix = pos_to_prefix(keyable);
+        let mut game_moves = HashMap::new();
+        iter.seek(&prefix);
+        while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+            if !key.starts_with(&prefix) { break; }
+            if let Some(uci) = legal_uci(pos, &key[prefix.len()..]) {
+                game_moves.insert(uci, GameWins::from_bytes(value));
+            }
+            iter.next();
         }
-        println!("Writing to DB");
-        self.db
-            .write(batch)
-            .expect("Failed to write batch to rocks.");
-        println!("Written to DB");
+        iter.status()?;
+        Ok(game_moves)
+    }
+
+    pub fn get_pos_wins(&self, keyable: &[u8]) -> Result<Option<GameWins>, rocksdb::Error> {
+        let bytes = self.db.get_pinned_cf(self.cf, pos_to_key(keyable))?;
+        Ok(bytes.map(|bytes| GameWins::from_bytes(&bytes)))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use shakmaty::{san::San, Position};
+    use shakmaty::{fen::Fen, san::San, Position};
     #[test]
     fn prefix_of_key_test() {
         let mut board = Chess::new();
@@ -152,4 +305,15 @@ mod tests {
 
         assert_eq!(prefix, &key[..prefix.len()]);
     }
+
+    #[test]
+    fn ingest_marker_spans_one_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open_default(dir.path()).unwrap();
+        assert!(!ingesting(&db));
+        begin_generation(&db).unwrap();
+        assert_eq!((ingesting(&db), generation(&db)), (true, 0));
+        assert_eq!(bump_generation(&db).unwrap(), 1);
+        assert!(!ingesting(&db));
+    }
 }
@@ -1,31 +1,286 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 // Put the helpers right above the struct so the names stay private.
 const fn default_min_rating() -> u32   { 0 }
 const fn default_cache_size() -> usize { 1_000_000 }
+const fn default_hot_plies() -> usize { 6 }
+const fn default_prefix_plies() -> usize { 12 }
+const fn default_dedup() -> bool { false }
+fn default_dataset() -> String { rocksdb::DEFAULT_COLUMN_FAMILY_NAME.into() }
+const fn default_catch_up_secs() -> u64 { 10 }
+const fn default_response_cache() -> usize { 10_000 }
+fn default_bind() -> Vec<String> { vec!["127.0.0.1".into()] }
+const fn default_port() -> u16 { 9090 }
+const fn default_request_timeout_secs() -> u64 { 30 }
+const fn default_trusted_proxies() -> usize { 1 }
+fn default_readers() -> usize { (num_cpus::get() / 4).max(1) }
 
 /// Shape of the JSON config expected by the `ingest` sub‑command.
 #[derive(Clone, Debug, Deserialize)]
 pub struct Ingest {
     /// `RocksDB` path. Created if it does not exist.
     pub db_path: String,
+    /// Dataset (column family) the games go into; `default` is the
+    /// database's original keyspace.
+    #[serde(default = "default_dataset")]
+    pub dataset: String,
+    /// Further datasets filled from the same archives in the same pass,
+    /// each with its own filters.
+    #[serde(default)]
+    pub datasets: Vec<Dataset>,
     /// Minimum ply to keep a game.
     pub min_ply_count: u32,
     /// Time Controls allowed
     pub time_controls: Vec<String>,
+    /// Which PGN header conventions the archives follow; see `Profile`.
+    #[serde(default)]
+    pub source_profile: Profile,
     /// Minimum Elo for White *and* Black to keep a game.
     #[serde(default = "default_min_rating")]
     pub min_rating: u32,
-    /// Max entries kept in the in‑memory `RocksDB` write‑cache.
+    /// Max entries kept in each worker's in‑memory `RocksDB` write‑cache.
+    /// The cache also flushes once it reaches its share of the memory budget.
     #[serde(default = "default_cache_size")]
     pub cache_size: usize,
-    /// Location of `.pgn.zst` archives to process.
+    /// Location of `.pgn.zst` archives (and Lichess `.ndjson[.zst|.gz]`
+    /// exports) to process.
+    #[serde(default)]
     pub pgn_dir: String,
+    /// Archives streamed over HTTP(S) instead of read from `pgn_dir`.
+    /// Interrupted downloads resume with `Range` requests.
+    #[serde(default)]
+    pub urls: Vec<String>,
+    /// `sha256sum`‑style file listing digests for `urls`, e.g. Lichess'
+    /// `sha256sums.txt`.  When set, every URL must be listed and match.
+    /// Archives are hashed as they stream in; one that does not match fails
+    /// like any other unreadable archive.
+    #[serde(default)]
+    pub checksum_url: Option<String>,
+    /// Reader threads decompressing and parsing archives in parallel.
+    /// Multi‑frame `.zst` archives are also split between readers.
+    #[serde(default = "default_readers")]
+    pub readers: usize,
+    /// Positions shallower than this many plies are summed in one table
+    /// shared by all workers and written once at the end of the run.
+    #[serde(default = "default_hot_plies")]
+    pub hot_plies: usize,
+    /// Opening moves each worker caches while replaying games, so common
+    /// prefixes are not converted and hashed again. `0` disables the cache.
+    /// The caches share a sixteenth of the memory budget, see `Budget`.
+    #[serde(default = "default_prefix_plies")]
+    pub prefix_plies: usize,
+    /// Skip games whose fingerprint (`GameId`/`Site`, else players, date,
+    /// round, start time and moves) was already ingested from another
+    /// archive; off by default.  A run's fingerprints are held within its
+    /// memory budget and spill to the database beyond it.
+    #[serde(default = "default_dedup")]
+    pub dedup: bool,
+    /// `merge` (default) streams merge operands into the database; `bulk`
+    /// builds SST files offline and ingests them at the end.
+    #[serde(default)]
+    pub mode: Mode,
+    /// Scratch directory for bulk‑load runs and SST files.
+    /// Defaults to `<db_path>.bulk`.
+    #[serde(default)]
+    pub spill_dir: Option<String>,
+    /// Memory, thread and queue limits for the run.
+    #[serde(default)]
+    pub resources: Resources,
+    /// Address such as `127.0.0.1:9091` to expose Prometheus metrics on
+    /// while ingesting.
+    #[serde(default)]
+    pub metrics_addr: Option<String>,
 }
 
-/// Shape of the JSON config expected by the `ingest` sub‑command.
+/// How ingested statistics reach `RocksDB`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Merge operands through the memtable.
+    #[default]
+    Merge,
+    /// Sorted runs on disk turned into SST files; fastest for first builds.
+    Bulk,
+}
+
+/// An extra dataset; unset filters inherit the top‑level ones.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Dataset {
+    pub name: String,
+    pub min_ply_count: Option<u32>,
+    pub time_controls: Option<Vec<String>>,
+    pub min_rating: Option<u32>,
+}
+
+/// Header dialect of the PGN source that `time_controls` and `min_rating` read.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    /// `Event` ("Rated Blitz game") and `WhiteElo`; a missing one passes.
+    #[default]
+    Lichess,
+    /// Speed from `TimeControl` (`180+2`, `1/86400`); no rated flag.
+    Chesscom,
+    /// TWIC / FIDE: `TimeControl`, else the event; `0` ratings are unrated.
+    Otb,
+}
+
+/// Limits of an ingest run; unset ones come from the machine, see `budget`.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Resources {
+    /// Bytes for all caches and `RocksDB`; half the available memory if unset.
+    pub memory_budget: Option<u64>,
+    /// Worker threads; the number of CPUs if unset.
+    pub threads: Option<usize>,
+    /// Games queued for the workers; `max(4096, cache_size / 16)` if unset.
+    pub channel_capacity: Option<usize>,
+}
+
+/// Shape of the JSON config expected by the `serve` sub‑command.
 #[derive(Clone, Debug, Deserialize)]
 pub struct Server {
     /// `RocksDB` path. Created if it does not exist.
     pub db_path: String,
+    /// Serve as a `RocksDB` secondary instance that follows a running
+    /// ingest, keeping its own logs in this directory.  Without it the
+    /// server reads a read‑only snapshot taken at startup.
+    #[serde(default)]
+    pub secondary_path: Option<String>,
+    /// Seconds between catch‑ups with the ingesting primary.
+    #[serde(default = "default_catch_up_secs")]
+    pub catch_up_secs: u64,
+    /// Responses kept in memory; `0` disables the cache.
+    #[serde(default = "default_response_cache")]
+    pub response_cache: usize,
+    /// Table of named openings for the `opening` of `/lichess`, see
+    /// `openings`; `null` openings without it.
+    #[serde(default)]
+    pub openings: Option<String>,
+    /// Addresses to listen on, e.g. `0.0.0.0` or `::1`.
+    #[serde(default = "default_bind")]
+    pub bind: Vec<String>,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// HTTP worker threads. Defaults to the number of CPUs.
+    #[serde(default)]
+    pub workers: Option<usize>,
+    /// Requests taking longer are answered with 503.
+    #[serde(default = "default_request_timeout_secs")]
+    pub request_timeout_secs: u64,
+    /// Origins browsers may call the API from; `*` allows any.  Empty
+    /// disables CORS.
+    #[serde(default)]
+    pub cors_origins: Vec<String>,
+    /// Serve HTTPS instead of HTTP.
+    #[serde(default)]
+    pub tls: Option<Tls>,
+    /// Clients identified by an `X-Api-Key` (or `Authorization: Bearer`)
+    /// header, each with its own limits.
+    #[serde(default)]
+    pub api_keys: Vec<ApiKey>,
+    /// Reject requests without a known key; otherwise they are limited by
+    /// `ip_rate`.  `/health` and `/metrics` are exempt from both, so load
+    /// balancers and Prometheus need no key; the public `/metrics` only
+    /// shows totals.
+    #[serde(default)]
+    pub require_api_key: bool,
+    /// Limit of each client IP sending no key; unlimited if unset.
+    #[serde(default)]
+    pub ip_rate: Option<Rate>,
+    /// Take the client IP from `X-Forwarded-For`; only safe behind a
+    /// reverse proxy that appends to it.
+    #[serde(default)]
+    pub trust_forwarded_for: bool,
+    /// Reverse proxies in front of the server, each appending the address
+    /// it saw to `X-Forwarded-For`.  The client is the entry this many from
+    /// the right; the ones before it are whatever the client sent.
+    #[serde(default = "default_trusted_proxies")]
+    pub trusted_proxies: usize,
+    /// Address such as `127.0.0.1:9091` for a private copy of `/metrics`
+    /// that also breaks usage down by API key; the public one only shows
+    /// totals.
+    #[serde(default)]
+    pub metrics_addr: Option<String>,
+}
+
+/// A client of the API.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ApiKey {
+    /// Shown in metrics instead of the key.
+    pub name: String,
+    pub key: String,
+    /// Unlimited if unset.
+    #[serde(default)]
+    pub rate: Option<Rate>,
+    /// Requests per UTC day, each position of a batch counting as one;
+    /// unlimited if unset.
+    #[serde(default)]
+    pub daily_quota: Option<u64>,
+}
+
+/// Token bucket: `per_second` requests on average, `burst` at once.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct Rate {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+/// PEM files of the server certificate chain and its private key.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Tls {
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+/// Ingest config for tests: `db_path` `db`, no ply or time‑control filter,
+/// then every field of `overrides`.
+#[cfg(test)]
+pub fn test_ingest(overrides: serde_json::Value) -> Ingest {
+    let mut cfg = serde_json::json!({ "db_path": "db", "pgn_dir": "", "min_ply_count": 0, "time_controls": [] });
+    if let (Some(cfg), serde_json::Value::Object(overrides)) = (cfg.as_object_mut(), overrides) {
+        cfg.extend(overrides);
+    }
+    serde_json::from_value(cfg).expect("test ingest config")
+}
+
+/// Scratch ingest for tests: a temporary directory holding the database and
+/// a `pgn` directory of archives, read by one reader for Lichess blitz games
+/// within a small memory budget, then every field of `overrides`.
+#[cfg(test)]
+pub struct TestArchives {
+    pub dir: tempfile::TempDir,
+    pub cfg: Ingest,
+}
+
+#[cfg(test)]
+impl TestArchives {
+    pub fn new(overrides: serde_json::Value) -> Self {
+        let dir = tempfile::tempdir().expect("test dir");
+        let pgn = dir.path().join("pgn");
+        std::fs::create_dir(&pgn).expect("test pgn dir");
+        let mut cfg = serde_json::json!({
+            "db_path": dir.path().join("db"), "pgn_dir": pgn, "time_controls": ["blitz"], "readers": 1,
+            "resources": { "memory_budget": 64 << 20, "threads": 2, "channel_capacity": 4096 },
+        });
+        if let (Some(cfg), serde_json::Value::Object(overrides)) = (cfg.as_object_mut(), overrides) {
+            cfg.extend(overrides);
+        }
+        Self { cfg: test_ingest(cfg), dir }
+    }
+
+    /// Write `pgn` as the zstd archive `pgn/<name>`; returns its path.
+    pub fn add(&self, name: &str, pgn: &str) -> String {
+        self.add_bytes(name, &zstd::encode_all(pgn.as_bytes(), 3).expect("compress test archive"))
+    }
+
+    /// Write `bytes` to `pgn/<name>` as they are, e.g. a broken archive.
+    pub fn add_bytes(&self, name: &str, bytes: &[u8]) -> String {
+        let path = std::path::Path::new(&self.cfg.pgn_dir).join(name);
+        std::fs::write(&path, bytes).expect("write test archive");
+        path.to_string_lossy().into_owned()
+    }
+
+    pub fn session(&self) -> crate::ingest::Session {
+        crate::ingest::Session::open(&self.cfg).expect("open test session")
+    }
 }
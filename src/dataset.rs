@@ -0,0 +1,146 @@
+//! dataset.rs – named datasets, one column family each.
+//!
+//! Every dataset has its own `ps` / `pms` / `fs` / `gs` keyspace in a column
+//! family named after it, opened with the database's options, so each has
+//! the merge operator and prefix extractor and shares the block cache and
+//! write buffers.  `default` is the keyspace databases had before datasets,
+//! so existing databases are simply the `default` dataset.
+
+use anyhow::{ensure, Context, Result as AnyResult};
+use crate::chess_db::SETTINGS;
+use crate::config::{self, Profile};
+use crate::extractor::Filters;
+use rocksdb::{ColumnFamily, ColumnFamilyDescriptor, Options, DB, DEFAULT_COLUMN_FAMILY_NAME};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// A game's datasets are a bit mask, see `GameSummary::datasets`.
+pub const MAX_DATASETS: usize = 64;
+
+/// Datasets present in the database at `path`; just `default` if there is
+/// no database yet.
+pub fn list(opts: &Options, path: &str) -> AnyResult<Vec<String>> {
+    if !Path::new(path).join("CURRENT").exists() {
+        return Ok(vec![DEFAULT_COLUMN_FAMILY_NAME.into()]);
+    }
+    DB::list_cf(opts, path).with_context(|| format!("list datasets of {path:?}"))
+}
+
+/// Open `path` with every existing dataset plus `wanted`, creating missing
+/// ones.
+pub fn open(opts: &Options, path: &str, wanted: &[String]) -> AnyResult<DB> {
+    let mut names = list(opts, path)?;
+    for name in wanted {
+        if !names.contains(name) { names.push(name.clone()); }
+    }
+    let mut opts = opts.clone();
+    opts.create_missing_column_families(true);
+    DB::open_cf_descriptors(&opts, path, descriptors(&opts, &names))
+        .with_context(|| format!("open {path:?}"))
+}
+
+/// Open every dataset of an existing database without taking its lock.
+pub fn open_read_only(opts: &Options, path: &str) -> AnyResult<DB> {
+    let names = list(opts, path)?;
+    DB::open_cf_descriptors_read_only(opts, path, descriptors(opts, &names), false)
+        .with_context(|| format!("open {path:?} read-only"))
+}
+
+/// Open every dataset as a secondary instance following the primary at
+/// `path`; `secondary` holds the instance's own logs.
+pub fn open_secondary(opts: &Options, path: &str, secondary: &str) -> AnyResult<DB> {
+    let names = list(opts, path)?;
+    DB::open_cf_descriptors_as_secondary(opts, path, secondary, descriptors(opts, &names))
+        .with_context(|| format!("open {path:?} as secondary in {secondary:?}"))
+}
+
+/// Column families named `names`, each with `opts`: plain names would get
+/// `RocksDB`'s defaults, without the merge operator.
+fn descriptors(opts: &Options, names: &[String]) -> Vec<ColumnFamilyDescriptor> {
+    names.iter().map(|name| ColumnFamilyDescriptor::new(name, opts.clone())).collect()
+}
+
+/// The datasets one ingest run writes, indexed like the bits of
+/// `GameSummary::datasets`: the config's `dataset` first, then `datasets`.
+#[derive(Clone, Debug)]
+pub struct Datasets {
+    names: Vec<String>,
+}
+
+impl Datasets {
+    pub fn from_config(cfg: &config::Ingest) -> AnyResult<Self> {
+        let mut names = vec![cfg.dataset.clone()];
+        for ds in &cfg.datasets {
+            ensure!(!names.contains(&ds.name), "dataset {:?} listed twice", ds.name);
+            names.push(ds.name.clone());
+        }
+        ensure!(names.len() <= MAX_DATASETS, "at most {MAX_DATASETS} datasets per run");
+        Ok(Self { names })
+    }
+
+    #[must_use] pub fn names(&self) -> &[String] { &self.names }
+
+    #[must_use] pub fn len(&self) -> usize { self.names.len() }
+
+    #[must_use] pub fn is_empty(&self) -> bool { self.names.is_empty() }
+
+    /// Mask with every dataset's bit set.
+    #[must_use] pub fn all(&self) -> u64 { u64::MAX >> (64 - self.names.len()) }
+
+    /// Column family of dataset `ds`; panics if the database was not opened
+    /// through `open` with these datasets.
+    #[must_use] pub fn cf<'a>(&self, db: &'a DB, ds: u8) -> &'a ColumnFamily {
+        db.cf_handle(&self.names[usize::from(ds)]).expect("dataset column family missing")
+    }
+
+    /// Column families of all datasets, by index.
+    #[must_use] pub fn cfs<'a>(&self, db: &'a DB) -> Vec<&'a ColumnFamily> {
+        (0..self.names.len()).map(|ds| self.cf(db, ds as u8)).collect()
+    }
+}
+
+/// How the last ingest into a dataset chose its games, kept in the dataset
+/// so a server can tell what it is looking at.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Settings {
+    pub filters: Filters,
+    pub source_profile: Profile,
+    pub dedup: bool,
+    /// Unix seconds.
+    pub ingested_at: i64,
+}
+
+/// Record the settings of `cfg` in every dataset it ingests into.
+pub fn record_settings(db: &DB, datasets: &Datasets, cfg: &config::Ingest) -> AnyResult<()> {
+    let ingested_at = chrono::Utc::now().timestamp();
+    for (cf, filters) in datasets.cfs(db).into_iter().zip(Filters::for_datasets(cfg)) {
+        let settings = Settings { filters, source_profile: cfg.source_profile, dedup: cfg.dedup, ingested_at };
+        db.put_cf(cf, SETTINGS, serde_json::to_vec(&settings)?)?;
+    }
+    Ok(())
+}
+
+/// Settings of the last ingest into `cf`, if one recorded them.
+#[must_use] pub fn settings(db: &DB, cf: &ColumnFamily) -> Option<Settings> {
+    serde_json::from_slice(&db.get_pinned_cf(cf, SETTINGS).ok()??).ok()
+}
+
+/// The bits of `mask` whose index satisfies `keep`.
+pub fn retain(mask: u64, mut keep: impl FnMut(u8) -> bool) -> u64 {
+    indices(mask).filter(|&ds| keep(ds)).fold(0, |m, ds| m | 1 << ds)
+}
+
+/// `retain` with a fallible `keep`; stops at the first error.
+pub fn try_retain<E>(mask: u64, mut keep: impl FnMut(u8) -> Result<bool, E>) -> Result<u64, E> {
+    indices(mask).try_fold(0, |m, ds| Ok(if keep(ds)? { m | 1 << ds } else { m }))
+}
+
+/// Indices of the bits set in `mask`.
+pub fn indices(mut mask: u64) -> impl Iterator<Item = u8> {
+    std::iter::from_fn(move || {
+        if mask == 0 { return None; }
+        let ds = mask.trailing_zeros() as u8;
+        mask &= mask - 1;
+        Some(ds)
+    })
+}
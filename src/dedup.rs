@@ -0,0 +1,279 @@
+//! dedup.rs – game fingerprints and the persistent seen‑set.
+//!
+//! Monthly dumps, per‑player exports and broadcast PGNs overlap, and
+//! `file::id` only catches whole files that were already ingested.  Every
+//! game gets a 16‑byte fingerprint: a hash of its permanent id (`GameId`,
+//! `Link`, or a URL in `Site`) when there is one, else of the players, the
+//! date, the round, the start time and the moves.  Fingerprints live under
+//! the `gs` key family of each dataset, so a game is counted once per
+//! dataset no matter which archive it arrives in.
+//!
+//! Without an id the match is only as good as the headers: two games with
+//! the same players, moves and day count once when their archives carry
+//! neither `Round` nor `UTCTime`, and one game exported with differently
+//! spelled names or dates counts twice.
+//!
+//! A run's fingerprints only become `gs` keys once its counts are on disk,
+//! so a crash never leaves a game marked as seen whose counts were lost.
+//! Until then they are held in memory, up to their share of the memory
+//! budget, and beyond it spilled under `gp` keys that lookups also check.
+//! A crashed run's `gp` keys are dropped when the next run starts.
+
+use crate::chess_db::{GP, GS};
+use crate::dataset::{self, Datasets};
+use rocksdb::{ColumnFamily, Direction, IteratorMode, ReadOptions, WriteBatch, WriteOptions, DB};
+use shakmaty::san::SanPlus;
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+pub type Fingerprint = [u8; 16];
+
+/// Rough memory of one claimed fingerprint, table overhead included.
+pub const ENTRY_BYTES: usize = 32;
+
+const SHARDS: usize = 64;
+
+/// First key after every `gp` key.
+const GP_END: &[u8] = b"gq";
+
+/// Fingerprints written per batch when a run's spilled claims are kept.
+const PROMOTE_BATCH: usize = 100_000;
+
+/// Fingerprint of a game with a permanent id such as
+/// `https://lichess.org/q7ZvsdUF`.
+#[must_use] pub fn by_id(id: &str) -> Fingerprint {
+    let mut h = blake3::Hasher::new();
+    h.update(b"id\0");
+    h.update(id.trim().as_bytes());
+    truncate(&h.finalize())
+}
+
+/// Fingerprint of a game without an id; `round` and `time` are empty when
+/// the archive does not have them.
+#[must_use] pub fn by_content(
+    white: &str,
+    black: &str,
+    date: &str,
+    round: &str,
+    time: &str,
+    sans: &[SanPlus],
+) -> Fingerprint {
+    let mut h = blake3::Hasher::new();
+    for field in [white, black, date, round, time] {
+        h.update(field.trim().as_bytes());
+        h.update(b"\0");
+    }
+    for san_plus in sans {
+        h.update(san_plus.san.to_string().as_bytes());
+        h.update(b" ");
+    }
+    truncate(&h.finalize())
+}
+
+fn truncate(hash: &blake3::Hash) -> Fingerprint {
+    let mut fp = [0; 16];
+    fp.copy_from_slice(&hash.as_bytes()[..16]);
+    fp
+}
+
+/// Fingerprints of the games this run claimed, in one shard.
+#[derive(Default)]
+struct Shard {
+    claimed: HashSet<(u8, Fingerprint)>,
+    /// Times the shard was spilled, so a claim can tell whether its disk
+    /// lookup raced with a spill.
+    spills: u64,
+}
+
+/// Fingerprints seen so far: those in `RocksDB` from earlier runs plus, per
+/// shard, the ones this run claimed.  The disk lookups happen outside the
+/// shard lock; the claim itself is an insert under it, so two workers never
+/// both get a game.
+pub struct SeenSet<'a> {
+    db: &'a DB,
+    datasets: &'a Datasets,
+    enabled: bool,
+    shards: Vec<Mutex<Shard>>,
+    /// Claims a shard holds before it spills them.
+    shard_capacity: usize,
+    /// Some shard has spilled, so lookups check `gp` keys too.
+    spilled: AtomicBool,
+    hasher: ahash::RandomState,
+    duplicates: AtomicU64,
+}
+
+impl<'a> SeenSet<'a> {
+    /// With `enabled == false` every game counts as new.  Claims beyond
+    /// `byte_limit` bytes are spilled to `RocksDB`.
+    pub fn new(
+        db: &'a DB,
+        datasets: &'a Datasets,
+        enabled: bool,
+        byte_limit: usize,
+    ) -> Result<Self, rocksdb::Error> {
+        if enabled {
+            // Left by a run that crashed before its counts were written.
+            for cf in datasets.cfs(db) { db.delete_range_cf(cf, GP, GP_END)?; }
+        }
+        Ok(Self {
+            db,
+            datasets,
+            enabled,
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            shard_capacity: (byte_limit / ENTRY_BYTES / SHARDS).max(1),
+            spilled: AtomicBool::new(false),
+            hasher: ahash::RandomState::new(),
+            duplicates: AtomicU64::new(0),
+        })
+    }
+
+    /// Record `fp` in each dataset of `datasets` and return those where it
+    /// was not seen before.
+    pub fn insert(&self, fp: &Fingerprint, datasets: u64) -> Result<u64, rocksdb::Error> {
+        if !self.enabled { return Ok(datasets); }
+        let shard = &self.shards[(self.hasher.hash_one(fp) as usize) % SHARDS];
+
+        let (unclaimed, spills) = {
+            let shard = shard.lock().expect("seen shard poisoned");
+            (dataset::retain(datasets, |ds| !shard.claimed.contains(&(ds, *fp))), shard.spills)
+        };
+        let unseen = dataset::try_retain(unclaimed, |ds| {
+            let spilled = self.spilled.load(Ordering::Acquire);
+            Ok(!(self.stored(ds, &key(GS, fp))? || spilled && self.stored(ds, &key(GP, fp))?))
+        })?;
+        let new = if unseen == 0 {
+            0
+        } else {
+            let mut shard = shard.lock().expect("seen shard poisoned");
+            // A spill since the lookup may have moved another claim to disk.
+            let unseen = if shard.spills == spills {
+                unseen
+            } else {
+                dataset::try_retain(unseen, |ds| Ok(!self.stored(ds, &key(GP, fp))?))?
+            };
+            let new = dataset::retain(unseen, |ds| shard.claimed.insert((ds, *fp)));
+            if shard.claimed.len() >= self.shard_capacity { self.spill(&mut shard)?; }
+            new
+        };
+        if new == 0 { self.duplicates.fetch_add(1, Ordering::Relaxed); }
+        Ok(new)
+    }
+
+    /// Write every claimed fingerprint, spilled or not; called once the
+    /// run's counts are on disk.  Goes through the WAL, so a later `FS`
+    /// record never survives a crash without them.
+    pub fn flush(&self) -> Result<(), rocksdb::Error> {
+        for shard in &self.shards {
+            self.write(&mut shard.lock().expect("seen shard poisoned").claimed)?;
+        }
+        if self.spilled.load(Ordering::Acquire) {
+            for cf in self.datasets.cfs(self.db) { self.promote(cf)?; }
+        }
+        Ok(())
+    }
+
+    /// Games skipped because their fingerprint was already known in all of
+    /// their datasets.
+    #[must_use] pub fn duplicates(&self) -> u64 { self.duplicates.load(Ordering::Relaxed) }
+
+    fn stored(&self, ds: u8, key: &[u8]) -> Result<bool, rocksdb::Error> {
+        let cf = self.datasets.cf(self.db, ds);
+        Ok(self.db.get_pinned_cf(cf, key)?.is_some())
+    }
+
+    /// Move a shard's claims to `gp` keys.  They are dropped by the next
+    /// run if this one crashes, so they skip the WAL.
+    fn spill(&self, shard: &mut Shard) -> Result<(), rocksdb::Error> {
+        let cfs = self.datasets.cfs(self.db);
+        let mut batch = WriteBatch::default();
+        for (ds, fp) in shard.claimed.drain() { batch.put_cf(cfs[usize::from(ds)], key(GP, &fp), b""); }
+        let mut opts = WriteOptions::default();
+        opts.disable_wal(true);
+        self.db.write_opt(batch, &opts)?;
+        self.spilled.store(true, Ordering::Release);
+        shard.spills += 1;
+        Ok(())
+    }
+
+    fn write(&self, claimed: &mut HashSet<(u8, Fingerprint)>) -> Result<(), rocksdb::Error> {
+        if claimed.is_empty() { return Ok(()); }
+        let cfs = self.datasets.cfs(self.db);
+        let mut batch = WriteBatch::default();
+        for (ds, fp) in claimed.drain() { batch.put_cf(cfs[usize::from(ds)], key(GS, &fp), b""); }
+        self.db.write(batch)
+    }
+
+    /// Turn the spilled claims in `cf` into `gs` keys.
+    fn promote(&self, cf: &ColumnFamily) -> Result<(), rocksdb::Error> {
+        // `gp` is shorter than the prefix extractor, so seek in total order.
+        let mut opts = ReadOptions::default();
+        opts.set_total_order_seek(true);
+        opts.set_iterate_upper_bound(GP_END);
+        let mut batch = WriteBatch::default();
+        for item in self.db.iterator_cf_opt(cf, opts, IteratorMode::From(GP, Direction::Forward)) {
+            let (k, _) = item?;
+            let fp: Fingerprint = k[GP.len()..].try_into().expect("gp key of a fingerprint");
+            batch.put_cf(cf, key(GS, &fp), b"");
+            if batch.len() >= PROMOTE_BATCH {
+                self.db.write(std::mem::take(&mut batch))?;
+            }
+        }
+        self.db.write(batch)?;
+        self.db.delete_range_cf(cf, GP, GP_END)
+    }
+}
+
+/// `gs` (or `gp`) key of `fp`.
+fn key(family: &[u8], fp: &Fingerprint) -> [u8; 18] {
+    let mut k = [0; 18];
+    k[..2].copy_from_slice(family);
+    k[2..].copy_from_slice(fp);
+    k
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config, rocks_cfg};
+
+    #[test]
+    fn content_fingerprints_tell_rounds_and_start_times_apart() {
+        let sans = [SanPlus::from_ascii(b"e4").unwrap()];
+        let fp = |round, time| by_content("a", "b", "2024.01.01", round, time, &sans);
+        assert_eq!(fp("1", ""), fp("1", ""));
+        assert_ne!(fp("1", ""), fp("2", ""));
+        assert_ne!(fp("", "10:00:00"), fp("", "10:05:00"));
+        assert_ne!(fp("1", ""), fp("", "1"));
+    }
+
+    #[test]
+    fn spilled_claims_still_count_and_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = config::test_ingest(serde_json::json!({ "db_path": dir.path() }));
+        let datasets = Datasets::from_config(&cfg).unwrap();
+        let db = dataset::open(&rocks_cfg::tuned(), &cfg.db_path, datasets.names()).unwrap();
+        let fps: Vec<Fingerprint> = (0..1000u32).map(|i| by_id(&i.to_string())).collect();
+
+        // Room for one claim per shard: every claim spills at once.
+        let seen = SeenSet::new(&db, &datasets, true, ENTRY_BYTES * SHARDS).unwrap();
+        assert!(fps.iter().all(|fp| seen.insert(fp, 1).unwrap() == 1));
+        assert!(seen.spilled.load(Ordering::Acquire));
+        assert!(fps.iter().all(|fp| seen.insert(fp, 1).unwrap() == 0));
+        assert_eq!(seen.duplicates(), 1000);
+
+        // Nothing is seen for good until the run flushes.
+        let cf = datasets.cf(&db, 0);
+        assert!(db.get_cf(cf, key(GS, &fps[0])).unwrap().is_none());
+        seen.flush().unwrap();
+        assert!(fps.iter().all(|fp| db.get_cf(cf, key(GS, fp)).unwrap().is_some()));
+        assert!(fps.iter().all(|fp| db.get_cf(cf, key(GP, fp)).unwrap().is_none()));
+
+        // A crashed run's spilled claims are forgotten.
+        let crashed = by_id("crashed");
+        db.put_cf(cf, key(GP, &crashed), b"").unwrap();
+        let next = SeenSet::new(&db, &datasets, true, 1 << 20).unwrap();
+        assert_eq!(next.insert(&crashed, 1).unwrap(), 1);
+        assert_eq!(next.insert(&fps[0], 1).unwrap(), 0);
+    }
+}
@@ -1,7 +1,126 @@
 use pgn_reader::{Color, Outcome, RawHeader, Skip, Visitor};
 use shakmaty::san::SanPlus;
 use crossbeam_channel::Sender;
-use crate::{GameSummary, config};
+use crate::{GameSummary, config, dataset, dedup};
+use crate::config::Profile;
+use serde::{Deserialize, Serialize};
+
+/// Game filters from `config::Ingest`, shared by every input format.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Filters {
+    min_rating: u32,
+    min_ply_count: u32,
+    time_controls: Vec<String>,
+}
+
+impl Filters {
+    #[must_use] pub fn new(cfg: &config::Ingest) -> Self {
+        Self {
+            min_rating: cfg.min_rating,
+            min_ply_count: cfg.min_ply_count,
+            time_controls: cfg.time_controls.clone(),
+        }
+    }
+
+    /// Filters of every dataset in the run, indexed like
+    /// `dataset::Datasets`.
+    #[must_use] pub fn for_datasets(cfg: &config::Ingest) -> Vec<Self> {
+        let base = Self::new(cfg);
+        let extra = cfg.datasets.iter().map(|ds| Self {
+            min_rating: ds.min_rating.unwrap_or(base.min_rating),
+            min_ply_count: ds.min_ply_count.unwrap_or(base.min_ply_count),
+            time_controls: ds.time_controls.clone().unwrap_or_else(|| base.time_controls.clone()),
+        });
+        std::iter::once(base.clone()).chain(extra).collect()
+    }
+
+    /// A player's rating passes; unknown ratings never do.
+    #[must_use] pub fn rating(&self, rating: Option<u32>) -> bool {
+        rating.is_some_and(|r| r >= self.min_rating)
+    }
+
+    /// Whether a player without a rating may pass: a PGN game without the
+    /// header outside the Lichess profile, or an NDJSON game's AI opponent.
+    #[must_use] pub const fn allows_unrated(&self) -> bool { self.min_rating == 0 }
+
+    /// The event names an allowed time control and is not casual, e.g.
+    /// `Rated Blitz game`.
+    #[must_use] pub fn event(&self, event: &str) -> bool {
+        let ev_lc = event.trim_matches(&['\"', '\''][..]).to_ascii_lowercase();
+        let is_casual  = ev_lc.contains("casual");
+        self.speed(&ev_lc) && !is_casual
+    }
+
+    /// `speed` (lower case, e.g. `blitz`) is one of `time_controls`.
+    #[must_use] pub fn speed(&self, speed: &str) -> bool {
+        self.time_controls.iter().any(|w| speed.contains(w.as_str()))
+    }
+
+    #[must_use] pub const fn plies(&self, ply_count: u32) -> bool { ply_count >= self.min_ply_count }
+}
+
+/// Lichess speed category of a PGN `TimeControl` value, so sources that do
+/// not name the speed are filtered with the same `time_controls` words.
+///
+/// Handles `180+2`, `600`, multi‑period `40/7200:3600+30` (the first period
+/// counts), daily `1/86400` and `-` (no clock).  The estimate follows
+/// Lichess: base time plus 40 increments; values too large for that are
+/// `None`.
+#[must_use] pub fn speed_of(time_control: &str) -> Option<&'static str> {
+    let tc = time_control.trim().trim_matches('"');
+    if tc == "-" { return Some("correspondence"); }
+    let first = tc.split(':').next()?;
+    let (period, increment) = match first.split_once('+') {
+        Some((p, i)) => (p, i.parse::<u32>().ok()?),
+        None => (first, 0),
+    };
+    let base = match period.split_once('/') {
+        // one move per period: a daily game
+        Some(("1", _)) => return Some("correspondence"),
+        Some((_, secs)) => secs.parse::<u32>().ok()?,
+        None => period.parse::<u32>().ok()?,
+    };
+    Some(match increment.checked_mul(40)?.checked_add(base)? {
+        0..=29 => "ultrabullet",
+        30..=179 => "bullet",
+        180..=479 => "blitz",
+        480..=1499 => "rapid",
+        1500..=21_599 => "classical",
+        _ => "correspondence",
+    })
+}
+
+/// Speed named in an OTB event title; OTB games are classical unless the
+/// event says otherwise.
+fn speed_of_event(event: &str) -> &'static str {
+    let ev_lc = event.to_ascii_lowercase();
+    ["bullet", "blitz", "rapid"]
+        .into_iter()
+        .find(|s| ev_lc.contains(s))
+        .unwrap_or("classical")
+}
+
+/// Which player a rating header belongs to, if `key` is one under
+/// `profile`'s conventions.
+fn rating_header(profile: Profile, key: &[u8]) -> Option<Color> {
+    match (profile, key) {
+        (_, b"WhiteElo") => Some(Color::White),
+        (_, b"BlackElo") => Some(Color::Black),
+        (Profile::Chesscom | Profile::Otb, b"WhiteRating") => Some(Color::White),
+        (Profile::Chesscom | Profile::Otb, b"BlackRating") => Some(Color::Black),
+        (Profile::Otb, b"WhiteFideElo") => Some(Color::White),
+        (Profile::Otb, b"BlackFideElo") => Some(Color::Black),
+        _ => None,
+    }
+}
+
+/// What the headers said about one player's rating.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Rating {
+    Missing,
+    Unknown,
+    Known(u32),
+}
 
 /// Visitor that extracts the winner + SAN move list for each game that passes
 /// filtering, then sends it to the worker pool.
@@ -11,25 +130,84 @@ pub struct Extractor<'a> {
     sans: Vec<SanPlus>,
     skip_game: bool,
     ply_count: u32,
-    // filters
-    min_rating: u32,
-    min_ply_count: u32,
-    time_controls: Vec<String>,
+    filters: Vec<Filters>,
+    /// Datasets this archive is read for …
+    datasets: u64,
+    /// … and those whose header filters the current game passed.
+    passing: u64,
+    profile: Profile,
+    // headers read so far
+    ratings: [Rating; 2],
+    /// `None` without an `Event` header.
+    event: Option<String>,
+    time_control: Option<String>,
+    // for the game's fingerprint
+    game_id: Option<String>,
+    white: String,
+    black: String,
+    date: String,
+    round: String,
+    time: String,
 }
 
 impl<'a> Extractor<'a> {
-    #[must_use] pub fn new(tx: &'a Sender<GameSummary>, cfg: &config::Ingest) -> Self {
+    /// `datasets` masks the datasets of `cfg` the games are filtered for.
+    #[must_use] pub fn new(tx: &'a Sender<GameSummary>, cfg: &config::Ingest, datasets: u64) -> Self {
         Self {
             tx,
             winner: None,
             sans: Vec::new(),
             skip_game: false,
             ply_count: 0,
-            min_rating: cfg.min_rating,
-            min_ply_count: cfg.min_ply_count,
-            time_controls: cfg.time_controls.clone(),
+            filters: Filters::for_datasets(cfg),
+            datasets,
+            passing: 0,
+            profile: cfg.source_profile,
+            ratings: [Rating::Missing; 2],
+            event: None,
+            time_control: None,
+            game_id: None,
+            white: String::new(),
+            black: String::new(),
+            date: String::new(),
+            round: String::new(),
+            time: String::new(),
         }
     }
+
+    /// Record a rating header.  OTB databases write `0` or nothing for
+    /// unrated players; those count as if the header were absent.
+    fn rating(&mut self, color: Color, value: &[u8]) {
+        let rating = match btoi::btoi::<u32>(value).ok() {
+            Some(0) | None if self.profile == Profile::Otb => return,
+            Some(r) => Rating::Known(r),
+            None => Rating::Unknown,      // `?` and anything else non‑numeric
+        };
+        let slot = &mut self.ratings[usize::from(color.is_white())];
+        if !matches!(slot, Rating::Known(_)) { *slot = rating; }
+    }
+
+    fn passes(&self, filters: &Filters) -> bool {
+        let ratings = self.ratings.iter().all(|r| match *r {
+            Rating::Missing => self.profile == Profile::Lichess || filters.allows_unrated(),
+            Rating::Unknown => false,
+            Rating::Known(r) => filters.rating(Some(r)),
+        });
+        let speed = match self.profile {
+            Profile::Lichess => self.event.as_deref().is_none_or(|e| filters.event(e)),
+            // no rated flag in the PGN; the clock decides the speed
+            Profile::Chesscom => self
+                .time_control
+                .as_deref()
+                .and_then(speed_of)
+                .is_some_and(|s| filters.speed(s)),
+            Profile::Otb => {
+                let speed = self.time_control.as_deref().and_then(speed_of);
+                filters.speed(speed.unwrap_or_else(|| speed_of_event(self.event.as_deref().unwrap_or(""))))
+            }
+        };
+        ratings && speed
+    }
 }
 
 impl Visitor for Extractor<'_> {
@@ -37,36 +215,56 @@ impl Visitor for Extractor<'_> {
 
     fn begin_headers(&mut self) {
         self.skip_game = false;
+        self.ratings = [Rating::Missing; 2];
+        self.event = None;
+        self.time_control = None;
+        self.game_id = None;
+        self.white.clear();
+        self.black.clear();
+        self.date.clear();
+        self.round.clear();
+        self.time.clear();
     }
 
     fn header(&mut self, key: &[u8], value: RawHeader) {
+        if let Some(color) = rating_header(self.profile, key) {
+            self.rating(color, value.as_bytes());
+            return;
+        }
         match key {
-            b"WhiteElo" | b"BlackElo" => {
-                if value.as_bytes() == b"?" {
-                    self.skip_game = true;
-                } else if let Ok(rating) = btoi::btoi::<u32>(value.as_bytes()) {
-                    if rating < self.min_rating {
-                        self.skip_game = true;
-                    }
-                } else {
-                    self.skip_game = true;
-                }
+            b"Event" => match std::str::from_utf8(value.as_bytes()) {
+                Ok(ev_raw) => self.event = Some(ev_raw.to_owned()),
+                Err(_) => self.skip_game = true,
+            },
+            b"TimeControl" => {
+                self.time_control = Some(String::from_utf8_lossy(value.as_bytes()).into_owned());
             }
-            b"Event" => {
-                if let Ok(ev_raw) = std::str::from_utf8(value.as_bytes()) {
-                    let ev_lc = ev_raw.trim_matches(&['\"', '\''][..]).to_ascii_lowercase();
-                    let has_time_controls = self.time_controls.iter().any(|w| ev_lc.contains(w));
-                    let is_casual  = ev_lc.contains("casual");
-                    if !has_time_controls || is_casual { self.skip_game = true; }
-                } else {
-                    self.skip_game = true;
+            // a permanent id; `Site` only counts when it is a game URL
+            b"GameId" | b"Link" | b"Site" => {
+                let v = String::from_utf8_lossy(value.as_bytes());
+                if self.game_id.is_none() && (key != b"Site" || v.starts_with("http")) {
+                    self.game_id = Some(v.into_owned());
                 }
             }
+            b"White" => self.white = String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            b"Black" => self.black = String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            b"Date" | b"UTCDate" if self.date.is_empty() => {
+                self.date = String::from_utf8_lossy(value.as_bytes()).into_owned();
+            }
+            b"Round" => self.round = String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            b"UTCTime" | b"Time" if self.time.is_empty() => {
+                self.time = String::from_utf8_lossy(value.as_bytes()).into_owned();
+            }
             _ => {}
         }
     }
 
-    fn end_headers(&mut self) -> Skip { Skip(self.skip_game) }
+    fn end_headers(&mut self) -> Skip {
+        self.passing =
+            dataset::retain(self.datasets, |ds| self.passes(&self.filters[usize::from(ds)]));
+        if self.passing == 0 { self.skip_game = true; }
+        Skip(self.skip_game)
+    }
 
     fn begin_game(&mut self) { self.ply_count = 0; self.sans.clear(); }
 
@@ -83,8 +281,26 @@ impl Visitor for Extractor<'_> {
     }
 
     fn end_game(&mut self) {
-        if !self.skip_game && self.ply_count >= self.min_ply_count {
-            let summary = GameSummary { winner: self.winner, sans: std::mem::take(&mut self.sans) };
+        let datasets =
+            dataset::retain(self.passing, |ds| self.filters[usize::from(ds)].plies(self.ply_count));
+        if !self.skip_game && datasets != 0 {
+            let fingerprint = match &self.game_id {
+                Some(id) => dedup::by_id(id),
+                None => dedup::by_content(
+                    &self.white,
+                    &self.black,
+                    &self.date,
+                    &self.round,
+                    &self.time,
+                    &self.sans,
+                ),
+            };
+            let summary = GameSummary {
+                winner: self.winner,
+                sans: std::mem::take(&mut self.sans),
+                fingerprint,
+                datasets,
+            };
             let _ = self.tx.send(summary); // ignore error on shutdown
         }
         self.sans.clear();
@@ -94,3 +310,75 @@ impl Visitor for Extractor<'_> {
 
     fn begin_variation(&mut self) -> Skip { Skip(true) }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_control_speeds() {
+        assert_eq!(speed_of("15+0"), Some("ultrabullet"));
+        assert_eq!(speed_of("60"), Some("bullet"));
+        assert_eq!(speed_of("180+2"), Some("blitz"));
+        assert_eq!(speed_of("600+5"), Some("rapid"));
+        assert_eq!(speed_of("5400+30"), Some("classical"));
+        assert_eq!(speed_of("40/7200:3600"), Some("classical"));
+        assert_eq!(speed_of("1/86400"), Some("correspondence"));
+        assert_eq!(speed_of("-"), Some("correspondence"));
+        assert_eq!(speed_of("?"), None);
+        assert_eq!(speed_of("60+4294967295"), None);
+        assert_eq!(speed_of("4294967295+1"), None);
+    }
+
+    /// Games of `pgn` the extractor keeps under `profile`, with a minimum
+    /// rating of `min_rating` and blitz only.
+    fn kept(profile: &str, min_rating: u32, pgn: &str) -> usize {
+        let cfg = config::test_ingest(serde_json::json!({
+            "time_controls": ["blitz"], "source_profile": profile, "min_rating": min_rating,
+        }));
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let mut vis = Extractor::new(&tx, &cfg, 1);
+        pgn_reader::BufferedReader::new(pgn.as_bytes()).read_all(&mut vis).unwrap();
+        drop(vis);
+        drop(tx);
+        rx.iter().count()
+    }
+
+    fn game(headers: &[(&str, &str)]) -> String {
+        let headers: String = headers.iter().map(|(k, v)| format!("[{k} \"{v}\"]\n")).collect();
+        format!("{headers}\n1. e4 e5 1-0\n\n")
+    }
+
+    #[test]
+    fn lichess_profile_ratings_and_events() {
+        let blitz = ("Event", "Rated Blitz game");
+        let rated = [("WhiteElo", "1600"), ("BlackElo", "1550")];
+        assert_eq!(kept("lichess", 1500, &game(&[blitz, rated[0], rated[1]])), 1);
+        assert_eq!(kept("lichess", 1500, &game(&[blitz, rated[0], ("BlackElo", "1400")])), 0);
+        // unknown ratings never pass; missing ones are not filtered on
+        assert_eq!(kept("lichess", 0, &game(&[blitz, rated[0], ("BlackElo", "?")])), 0);
+        assert_eq!(kept("lichess", 1500, &game(&[blitz])), 1);
+        assert_eq!(kept("lichess", 0, &game(&[blitz])), 1);
+        // no event: not filtered on it
+        assert_eq!(kept("lichess", 1500, &game(&rated)), 1);
+        assert_eq!(kept("lichess", 1500, &game(&[("Event", "Casual Blitz game"), rated[0], rated[1]])), 0);
+    }
+
+    #[test]
+    fn otb_and_chesscom_profiles() {
+        let otb = |ratings: [(&'static str, &'static str); 2]| {
+            game(&[("Event", "World Blitz"), ratings[0], ratings[1]])
+        };
+        // `0` and `?` mean unrated at OTB events
+        assert_eq!(kept("otb", 0, &otb([("WhiteElo", "0"), ("BlackElo", "?")])), 1);
+        assert_eq!(kept("otb", 1500, &otb([("WhiteElo", "0"), ("BlackFideElo", "2400")])), 0);
+        assert_eq!(kept("otb", 1500, &otb([("WhiteFideElo", "2500"), ("BlackFideElo", "2400")])), 1);
+        // classical unless the event or clock says otherwise
+        assert_eq!(kept("otb", 0, &game(&[("Event", "Olympiad")])), 0);
+
+        let clock = |tc| game(&[("TimeControl", tc), ("WhiteRating", "1600"), ("BlackRating", "1600")]);
+        assert_eq!(kept("chesscom", 1500, &clock("180+2")), 1);
+        assert_eq!(kept("chesscom", 1500, &clock("600")), 0);
+        assert_eq!(kept("chesscom", 0, &game(&[])), 0);
+    }
+}
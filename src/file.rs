@@ -1,12 +1,195 @@
+//! file.rs – identity and ingest records of archives.
+//!
+//! An archive is identified by a BLAKE3 digest of its size and its first
+//! and last `SAMPLE_BYTES`, so moving, renaming or re‑downloading it (or a
+//! toolchain upgrade) does not make it look new; see `by_validator` for
+//! servers that cannot send those bytes alone.  The `FS` record under
+//! that digest keeps where the archive came from and when it was ingested.
+
+use anyhow::{Context, Result as AnyResult};
+use crate::chess_db::FS;
+use rocksdb::{ColumnFamily, Direction, IteratorMode, ReadOptions, DB};
+use serde::{Deserialize, Serialize};
 use std::{fs, hash::{Hash, Hasher}, collections::hash_map::DefaultHasher};
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::time::UNIX_EPOCH;
+
+/// Bytes hashed from each end of an archive.
+pub const SAMPLE_BYTES: u64 = 4 << 20;
+
+pub type Id = [u8; 16];
+
+/// Byte ranges hashed for an archive of `len` bytes: the head, and the
+/// tail that does not overlap it (empty for small files).
+#[must_use] pub fn sample_ranges(len: u64) -> [Range<u64>; 2] {
+    let head = len.min(SAMPLE_BYTES);
+    let tail = (len - head).min(SAMPLE_BYTES);
+    [0..head, len - tail..len]
+}
+
+/// Identity of an archive of `len` bytes from its `sample_ranges`.
+#[must_use] pub fn digest(len: u64, head: &[u8], tail: &[u8]) -> Id {
+    let mut h = blake3::Hasher::new();
+    h.update(&len.to_le_bytes());
+    h.update(head);
+    h.update(tail);
+    let mut id = [0; 16];
+    id.copy_from_slice(&h.finalize().as_bytes()[..16]);
+    id
+}
+
+/// Identity of a remote archive that cannot be sampled, from its size and
+/// its `ETag` or `Last-Modified`.  It never equals a content digest, so a
+/// copy of the archive from elsewhere counts as another archive.
+#[must_use] pub fn by_validator(len: u64, validator: &str) -> Id {
+    let mut h = blake3::Hasher::new();
+    h.update(b"validator\0");
+    h.update(&len.to_le_bytes());
+    h.update(validator.as_bytes());
+    let mut id = [0; 16];
+    id.copy_from_slice(&h.finalize().as_bytes()[..16]);
+    id
+}
+
+/// Content identity of a local archive.
+pub fn id(path: &str) -> AnyResult<Id> {
+    let mut file = fs::File::open(path).with_context(|| format!("open {path:?}"))?;
+    let len = file.metadata()?.len();
+    let mut read = |r: Range<u64>| -> std::io::Result<Vec<u8>> {
+        let mut buf = vec![0; (r.end - r.start) as usize];
+        file.seek(SeekFrom::Start(r.start))?;
+        file.read_exact(&mut buf)?;
+        Ok(buf)
+    };
+    let [head, tail] = sample_ranges(len);
+    Ok(digest(len, &read(head)?, &read(tail)?))
+}
+
+/// `FS` key of an archive.
+#[must_use] pub fn key(id: &Id) -> Vec<u8> {
+    let mut key = Vec::from(FS);
+    key.extend_from_slice(id);
+    key
+}
 
-pub fn id(path: &str) -> anyhow::Result<[u8; 8]> {
-    let meta = fs::metadata(path)?;
+/// Key of a local archive under the old path/size/mtime identity, for
+/// databases ingested before content digests.
+#[must_use] pub fn legacy_key(path: &str) -> Option<Vec<u8>> {
+    let meta = fs::metadata(path).ok()?;
     let mut h = DefaultHasher::new();
     path.hash(&mut h);                   // absolute path
     meta.len().hash(&mut h);             // file size
-    meta.modified()?.duration_since(std::time::UNIX_EPOCH)?
+    meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?
                   .as_secs()
                   .hash(&mut h);         // mtime, 1 s resolution
-    Ok(h.finish().to_be_bytes())
+    Some(legacy(h.finish()))
+}
+
+/// Old identity of a remote archive: its URL plus the server's validator.
+#[must_use] pub fn legacy_url_key(url: &str, len: u64, validator: Option<&str>) -> Vec<u8> {
+    let mut h = DefaultHasher::new();
+    url.hash(&mut h);
+    len.hash(&mut h);
+    validator.hash(&mut h);              // ETag or Last-Modified
+    legacy(h.finish())
+}
+
+fn legacy(hash: u64) -> Vec<u8> {
+    let mut key = Vec::from(FS);
+    key.extend_from_slice(&hash.to_be_bytes());
+    key
+}
+
+/// Value of an `FS` record.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Record {
+    /// Path or URL the archive was ingested from; empty for old records.
+    pub path: String,
+    pub size: u64,
+    /// Modification time (Unix seconds) of a local archive.
+    pub modified: Option<i64>,
+    /// Unix seconds.
+    pub ingested_at: i64,
+    /// Read only up to an error, without dedup to make a retry safe; the
+    /// games before the error are counted, the rest never will be.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub partial: bool,
+}
+
+impl Record {
+    /// Stats of a local archive.
+    pub fn local(path: &str) -> AnyResult<Self> {
+        let meta = fs::metadata(path).with_context(|| format!("stat {path:?}"))?;
+        let modified = meta.modified().ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs() as i64);
+        Ok(Self { path: path.to_owned(), size: meta.len(), modified, ..Self::default() })
+    }
+
+    #[must_use] pub fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("serialize file record")
+    }
+
+    /// Old records hold only the ingest time, as big‑endian seconds.
+    #[must_use] pub fn from_bytes(bytes: &[u8]) -> Self {
+        if let Ok(ts) = <[u8; 8]>::try_from(bytes) {
+            return Self { ingested_at: i64::from_be_bytes(ts), ..Self::default() };
+        }
+        serde_json::from_slice(bytes).unwrap_or_default()
+    }
+}
+
+/// Every `FS` record in dataset `cf` with the key suffix (the archive id).
+pub fn ingested(db: &DB, cf: &ColumnFamily) -> AnyResult<Vec<(Vec<u8>, Record)>> {
+    // `FS` is shorter than the prefix extractor, so seek in total order.
+    let mut opts = ReadOptions::default();
+    opts.set_total_order_seek(true);
+    let mut out = Vec::new();
+    for item in db.iterator_cf_opt(cf, opts, IteratorMode::From(FS, Direction::Forward)) {
+        let (k, v) = item?;
+        if !k.starts_with(FS) { break; }
+        out.push((k[FS.len()..].to_vec(), Record::from_bytes(&v)));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn id_samples_both_ends() {
+        assert_eq!(sample_ranges(10), [0..10, 10..10]);
+        let len = 2 * SAMPLE_BYTES + 1000;
+        assert_eq!(sample_ranges(len), [0..SAMPLE_BYTES, len - SAMPLE_BYTES..len]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.pgn.zst");
+        let path = path.to_str().unwrap();
+        let mut body: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        fs::write(path, &body).unwrap();
+        let id_a = id(path).unwrap();
+        let [head, tail] = sample_ranges(len);
+        let (head, tail) = (head.start as usize..head.end as usize, tail.start as usize..tail.end as usize);
+        assert_eq!(id_a, digest(len, &body[head], &body[tail]));
+
+        // Only the sampled bytes and the size count, not the name or the middle.
+        let moved = dir.path().join("renamed.pgn.zst");
+        body[(len / 2) as usize] ^= 1;
+        fs::write(&moved, &body).unwrap();
+        assert_eq!(id(moved.to_str().unwrap()).unwrap(), id_a);
+        *body.last_mut().unwrap() ^= 1;
+        fs::write(&moved, &body).unwrap();
+        assert_ne!(id(moved.to_str().unwrap()).unwrap(), id_a);
+    }
+
+    #[test]
+    fn old_records_keep_their_ingest_time() {
+        let old = Record::from_bytes(&1_700_000_000i64.to_be_bytes());
+        assert_eq!((old.ingested_at, old.path.as_str()), (1_700_000_000, ""));
+        let new = Record { path: "a.pgn.zst".into(), size: 3, modified: Some(1), ingested_at: 2, partial: false };
+        let back = Record::from_bytes(&new.to_bytes());
+        assert_eq!((back.path, back.size, back.modified, back.ingested_at), (new.path, 3, Some(1), 2));
+    }
 }
@@ -0,0 +1,316 @@
+//! frames.rs – split multi‑frame `.zst` archives into chunks that several
+//! reader threads can decode in parallel.
+//!
+//! A zstd frame can be decoded without any of its neighbours, but PGN games
+//! do not respect frame boundaries.  Each chunk therefore drops the partial
+//! game it starts in (the previous chunk finishes it) and keeps decoding past
+//! its last frame until the next game starts: a tag line (`[`) right after a
+//! blank line.  No particular tag is assumed, as only Lichess puts `Event`
+//! first.
+//!
+//! Both neighbours must agree on where that is without seeing each other's
+//! bytes, so the line a chunk boundary falls in never starts a game and never
+//! counts as blank, whether or not the boundary split it.  A game followed
+//! past the end of its chunk is decoded chunk by chunk to apply the same rule
+//! at every boundary it crosses.
+
+use std::io::{self, BufRead, Read, Seek};
+use std::ops::Range;
+
+const FRAME_MAGIC: u32 = 0xFD2F_B528;
+const SKIPPABLE_MASK: u32 = 0xFFFF_FFF0;
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A50;
+
+
+/// Contiguous run of frames handed to one reader.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chunk {
+    /// Compressed byte range covered by the chunk's own frames.
+    pub bytes: Range<u64>,
+    /// The first chunk of a file has no partial game to drop.
+    pub first: bool,
+    /// Byte ranges of the file's later chunks, where the last game of this
+    /// one may end.
+    pub later: Vec<Range<u64>>,
+}
+
+/// Return the compressed byte range of every frame in a zstd stream.
+///
+/// Only frame and block headers are read; block payloads are skipped with
+/// relative seeks so scanning a large archive is cheap.
+pub fn scan<R: BufRead + Seek>(r: &mut R) -> io::Result<Vec<Range<u64>>> {
+    let mut frames = Vec::new();
+    let mut pos = 0u64;
+    loop {
+        let mut magic = [0u8; 4];
+        match r.read_exact(&mut magic) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        let magic = u32::from_le_bytes(magic);
+        let len = if magic & SKIPPABLE_MASK == SKIPPABLE_MAGIC {
+            let size = u64::from(u32::from_le_bytes(read_n::<4, _>(r)?));
+            r.seek_relative(i64::try_from(size).map_err(invalid)?)?;
+            8 + size
+        } else if magic == FRAME_MAGIC {
+            4 + skip_frame_body(r)?
+        } else {
+            return Err(invalid(format!("bad zstd magic at byte {pos}")));
+        };
+        frames.push(pos..pos + len);
+        pos += len;
+    }
+    Ok(frames)
+}
+
+/// Skip everything after the magic number; returns the bytes consumed.
+fn skip_frame_body<R: BufRead + Seek>(r: &mut R) -> io::Result<u64> {
+    let [fhd] = read_n::<1, _>(r)?;
+    let fcs_flag = fhd >> 6;
+    let single_segment = (fhd >> 5) & 1 == 1;
+    let has_checksum = (fhd >> 2) & 1 == 1;
+    let dict_len = [0, 1, 2, 4][usize::from(fhd & 3)];
+    let fcs_len = match fcs_flag {
+        0 => u64::from(single_segment),
+        1 => 2,
+        2 => 4,
+        _ => 8,
+    };
+    let header_rest = u64::from(!single_segment) + dict_len + fcs_len;
+    r.seek_relative(i64::try_from(header_rest).map_err(invalid)?)?;
+
+    let mut consumed = 1 + header_rest;
+    loop {
+        let [a, b, c] = read_n::<3, _>(r)?;
+        let header = u32::from_le_bytes([a, b, c, 0]);
+        let last = header & 1 == 1;
+        let size = u64::from(header >> 3);
+        let payload = match (header >> 1) & 3 {
+            0 | 2 => size, // raw / compressed
+            1 => 1,        // RLE: a single byte repeated `size` times
+            _ => return Err(invalid("reserved zstd block type")),
+        };
+        r.seek_relative(i64::try_from(payload).map_err(invalid)?)?;
+        consumed += 3 + payload;
+        if last { break; }
+    }
+    if has_checksum {
+        r.seek_relative(4)?;
+        consumed += 4;
+    }
+    Ok(consumed)
+}
+
+/// Group `frames` into at most `n` chunks of roughly equal compressed size.
+#[must_use]
+pub fn chunks(frames: &[Range<u64>], n: usize) -> Vec<Chunk> {
+    let Some(end) = frames.last().map(|f| f.end) else { return Vec::new() };
+    let target = (end / n.max(1) as u64).max(1);
+    let mut bounds = Vec::new();
+    let mut start = 0u64;
+    for f in frames {
+        if f.end - start >= target || f.end == end {
+            bounds.push(start..f.end);
+            start = f.end;
+        }
+    }
+    (0..bounds.len())
+        .map(|i| Chunk {
+            bytes: bounds[i].clone(),
+            first: i == 0,
+            later: bounds[i + 1..].to_vec(),
+        })
+        .collect()
+}
+
+/// Decompressed PGN for one chunk, trimmed to whole games.
+pub struct GameAligned {
+    head: Box<dyn BufRead + Send>,
+    /// Decoder of the later chunk being read past the end of this one.
+    tail: Option<Box<dyn BufRead + Send>>,
+    later: std::vec::IntoIter<Box<dyn Read + Send>>,
+    skip_leading: bool,
+    in_tail: bool,
+    done: bool,
+    /// The next line is the first one after a chunk boundary.
+    at_boundary: bool,
+    /// The last line checked by `starts_game` was blank.
+    after_blank: bool,
+    line: Vec<u8>,
+    pos: usize,
+}
+
+impl GameAligned {
+    /// `head` yields the chunk's own compressed frames, `later` those of
+    /// each later chunk (only read until the next game starts; empty for the
+    /// last chunk of a file).
+    pub fn new<H, T>(head: H, later: Vec<T>, first: bool) -> io::Result<Self>
+    where
+        H: Read + Send + 'static,
+        T: Read + Send + 'static,
+    {
+        let later: Vec<Box<dyn Read + Send>> = later
+            .into_iter()
+            .map(|r| Box::new(r) as Box<dyn Read + Send>)
+            .collect();
+        Ok(Self {
+            head: decoder(head)?,
+            tail: None,
+            later: later.into_iter(),
+            skip_leading: !first,
+            in_tail: false,
+            done: false,
+            at_boundary: !first,
+            after_blank: false,
+            line: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    /// Load the next line worth emitting into `self.line`.
+    fn next_line(&mut self) -> io::Result<()> {
+        self.line.clear();
+        self.pos = 0;
+        while !self.done {
+            if self.in_tail {
+                // A chunk that never saw a game start owns nothing.
+                if self.skip_leading {
+                    self.done = true;
+                    break;
+                }
+                let Some(tail) = &mut self.tail else {
+                    match self.later.next() {
+                        Some(next) => {
+                            self.tail = Some(decoder(next)?);
+                            self.at_boundary = true;
+                            continue;
+                        }
+                        None => {
+                            self.done = true;
+                            break;
+                        }
+                    }
+                };
+                if tail.read_until(b'\n', &mut self.line)? == 0 {
+                    self.tail = None;
+                    continue;
+                }
+                if self.starts_game() {
+                    self.line.clear();
+                    self.done = true;
+                }
+                return Ok(());
+            }
+            if self.head.read_until(b'\n', &mut self.line)? == 0 {
+                self.in_tail = true;
+                continue;
+            }
+            if self.skip_leading {
+                if !self.starts_game() {
+                    self.line.clear();
+                    continue;
+                }
+                self.skip_leading = false;
+            }
+            return Ok(());
+        }
+        Ok(())
+    }
+
+    /// Whether `self.line` is the first line of a game; called on every line
+    /// from a chunk boundary up to the game start it looks for.
+    fn starts_game(&mut self) -> bool {
+        let boundary = std::mem::take(&mut self.at_boundary);
+        let starts = !boundary && self.after_blank && self.line.first() == Some(&b'[');
+        self.after_blank = !boundary && self.line.iter().all(u8::is_ascii_whitespace);
+        starts
+    }
+}
+
+impl Read for GameAligned {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.line.len() {
+            self.next_line()?;
+        }
+        let n = buf.len().min(self.line.len() - self.pos);
+        buf[..n].copy_from_slice(&self.line[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+fn decoder<R: Read + Send + 'static>(r: R) -> io::Result<Box<dyn BufRead + Send>> {
+    Ok(Box::new(io::BufReader::new(zstd::stream::read::Decoder::new(r)?)))
+}
+
+fn read_n<const N: usize, R: Read>(r: &mut R) -> io::Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn invalid<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const PGN_A: &str = "[Event \"a\"]\n\n1. e4 e5 1-0\n\n[Event \"b\"]\n\n1. d4";
+    const PGN_B: &str = " d5 0-1\n\n[Event \"c\"]\n\n1. c4 1/2-1/2\n";
+
+    /// Everything the readers of `parts` emit, in chunk order.
+    fn read_chunks(all: &[u8], parts: &[Chunk]) -> String {
+        let slice = |r: &Range<u64>| Cursor::new(all[r.start as usize..r.end as usize].to_vec());
+        let mut text = String::new();
+        for c in parts {
+            let later = c.later.iter().map(slice).collect();
+            let mut r = GameAligned::new(slice(&c.bytes), later, c.first).unwrap();
+            r.read_to_string(&mut text).unwrap();
+        }
+        text
+    }
+
+    #[test]
+    fn scan_and_split_frames() {
+        let a = zstd::encode_all(PGN_A.as_bytes(), 3).unwrap();
+        let b = zstd::encode_all(PGN_B.as_bytes(), 3).unwrap();
+        let split = a.len() as u64;
+        let all = [a, b].concat();
+
+        let frames = scan(&mut Cursor::new(&all)).unwrap();
+        assert_eq!(frames, vec![0..split, split..all.len() as u64]);
+
+        let parts = chunks(&frames, 2);
+        assert_eq!(parts.len(), 2);
+        assert_eq!(read_chunks(&all, &parts), format!("{PGN_A}{PGN_B}"));
+    }
+
+    #[test]
+    fn games_without_an_event_tag_split_at_any_frame_boundary() {
+        // Chess.com and TWIC order their tags differently; movetext spans
+        // lines.
+        let pgn = "[Site \"Chess.com\"]\n[White \"a\"]\n[Result \"1-0\"]\n\n\
+            1. e4 e5 2. Qh5 Nc6\n3. Bc4 Nf6 4. Qxf7# 1-0\n\n\
+            [White \"b\"]\n[Black \"c\"]\n[Result \"0-1\"]\n\n1. f3 e5 2. g4 Qh4# 0-1\n\n\
+            [Date \"2024.01.01\"]\n[Result \"1/2-1/2\"]\n\n1. d4 d5\n2. c4 e6 1/2-1/2\n\n\
+            [Round \"4\"]\n[Result \"*\"]\n\n1. c4 *\n";
+        for step in [3, 7, 16, 41] {
+            let all: Vec<u8> = pgn.as_bytes()
+                .chunks(step)
+                .flat_map(|part| zstd::encode_all(part, 3).unwrap())
+                .collect();
+            let frames = scan(&mut Cursor::new(&all)).unwrap();
+            for n in 1..=frames.len().min(24) {
+                let text = read_chunks(&all, &chunks(&frames, n));
+                assert_eq!(text, pgn, "{step}-byte frames in {n} chunks");
+            }
+        }
+    }
+}
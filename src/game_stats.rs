@@ -1,4 +1,5 @@
 use serde::Serialize;
+use shakmaty::Color;
 use std::collections::HashMap;
 use std::convert::TryInto;
 
@@ -48,6 +49,27 @@ impl GameWins {
     #[must_use] pub const fn total(&self) -> u32 {
         self.black + self.white + self.draws
     }
+
+    /// Points per game for `color`, a draw counting half; `None` without
+    /// games.
+    #[must_use] pub fn score(&self, color: Color) -> Option<f64> {
+        let total = self.total();
+        if total == 0 { return None; }
+        let wins = match color { Color::White => self.white, Color::Black => self.black };
+        Some((f64::from(wins) + f64::from(self.draws) / 2.0) / f64::from(total))
+    }
+
+    /// 95 % Wilson interval of `score`, so a move played a handful of
+    /// times does not outrank a well‑tested one.
+    #[must_use] pub fn score_interval(&self, color: Color) -> Option<(f64, f64)> {
+        const Z: f64 = 1.96;
+        let p = self.score(color)?;
+        let n = f64::from(self.total());
+        let denom = 1.0 + Z * Z / n;
+        let center = (p + Z * Z / (2.0 * n)) / denom;
+        let half = Z * (p * (1.0 - p) / n + Z * Z / (4.0 * n * n)).sqrt() / denom;
+        Some(((center - half).max(0.0), (center + half).min(1.0)))
+    }
 }
 
 #[derive(Clone, Debug, Default, Serialize)]
@@ -64,3 +86,20 @@ impl GameStats {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wilson_interval_narrows_with_games() {
+        let few = GameWins { white: 3, black: 0, draws: 0 };
+        let many = GameWins { white: 300, black: 100, draws: 100 };
+        assert_eq!(few.score(Color::White), Some(1.0));
+        let (lo_few, hi_few) = few.score_interval(Color::White).unwrap();
+        let (lo_many, hi_many) = many.score_interval(Color::White).unwrap();
+        assert!(lo_few < lo_many && hi_few == 1.0);
+        assert!(lo_many < 0.7 && 0.7 < hi_many && hi_many - lo_many < 0.1);
+        assert_eq!(GameWins::new().score_interval(Color::Black), None);
+    }
+}
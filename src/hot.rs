@@ -0,0 +1,168 @@
+//! hot.rs – shared pre‑aggregation for the hottest, shallowest positions.
+//!
+//! The starting position and the first few plies of popular openings occur
+//! in nearly every game.  With per‑worker caches each of them became one
+//! merge operand per worker per flush; here they are summed once across all
+//! workers and written a single time when ingest finishes.
+
+use crate::chess_db::Key;
+use crate::game_stats::GameWins;
+use crate::worker::Sink;
+use ahash::RandomState;
+use anyhow::Result as AnyResult;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Worker‑local map of hot keys, folded into the shared table in batches so
+/// that workers do not take a lock for every position.
+pub type HotBatch = HashMap<Key, GameWins, RandomState>;
+
+const SHARDS: usize = 64;
+
+/// Lock‑sharded totals for positions shallower than `max_ply`.
+pub struct HotTable {
+    shards: Vec<Mutex<HotBatch>>,
+    hasher: RandomState,
+    max_ply: usize,
+    capacity: usize,
+    len: AtomicUsize,
+}
+
+impl HotTable {
+    /// `capacity` bounds the number of distinct keys; once full, new keys
+    /// are handed back to the caller's per‑worker path.
+    #[must_use] pub fn new(max_ply: usize, capacity: usize) -> Self {
+        let hasher = RandomState::new();
+        Self {
+            shards: (0..SHARDS)
+                .map(|_| Mutex::new(HotBatch::with_hasher(hasher.clone())))
+                .collect(),
+            hasher,
+            max_ply,
+            capacity,
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    #[must_use] pub fn batch(&self) -> HotBatch { HotBatch::with_hasher(self.hasher.clone()) }
+
+    #[inline] #[must_use] pub const fn is_hot(&self, ply: usize) -> bool { ply < self.max_ply }
+
+    /// Drain `batch` into the table, taking each shard lock once.  Keys that
+    /// no longer fit are passed to `overflow`.
+    pub fn absorb(&self, batch: &mut HotBatch, mut overflow: impl FnMut(Key, GameWins)) {
+        let mut by_shard: Vec<Vec<(Key, GameWins)>> = vec![Vec::new(); SHARDS];
+        for (k, v) in batch.drain() {
+            let shard = (self.hasher.hash_one(k) as usize) % SHARDS;
+            by_shard[shard].push((k, v));
+        }
+        for (shard, entries) in self.shards.iter().zip(by_shard) {
+            if entries.is_empty() { continue; }
+            let mut map = shard.lock().expect("hot shard poisoned");
+            for (k, v) in entries {
+                if let Some(e) = map.get_mut(&k) {
+                    *e = e.combine(&v);
+                } else if self.len.fetch_add(1, Ordering::Relaxed) < self.capacity {
+                    map.insert(k, v);
+                } else {
+                    self.len.fetch_sub(1, Ordering::Relaxed);
+                    overflow(k, v);
+                }
+            }
+        }
+    }
+
+    /// Write every total to `sink`; called once after all workers exit.
+    /// Returns the number of totals written.
+    pub fn flush(&self, sink: Sink) -> AnyResult<usize> {
+        let mut written = 0;
+        for shard in &self.shards {
+            let mut map = shard.lock().expect("hot shard poisoned");
+            if map.is_empty() { continue; }
+            written += sink.write(map.drain())?;
+        }
+        self.len.store(0, Ordering::Relaxed);
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bulk::{self, RunWriter};
+    use crate::config;
+    use crate::dataset::{self, Datasets};
+    use crate::merge::wins_merge_op;
+    use crate::rocks_cfg;
+
+    const fn wins(white: u32, black: u32, draws: u32) -> GameWins { GameWins { black, white, draws } }
+    const fn t(w: GameWins) -> (u32, u32, u32) { (w.white, w.black, w.draws) }
+
+    fn keys() -> [Key; 3] {
+        [b"psaaaaaaaa", b"psbbbbbbbb", b"pscccccccc"].map(|k| Key::from_slice(k))
+    }
+
+    #[test]
+    fn absorb_sums_batches_and_overflows_at_capacity() {
+        let [a, b, c] = keys();
+        let hot = HotTable::new(2, 2);
+        assert!(hot.is_hot(1) && !hot.is_hot(2));
+        let mut overflow = Vec::new();
+
+        let mut batch = hot.batch();
+        batch.insert(a, wins(1, 0, 0));
+        batch.insert(b, wins(0, 1, 0));
+        hot.absorb(&mut batch, |k, v| overflow.push((k, t(v))));
+        assert!(batch.is_empty() && overflow.is_empty());
+
+        // Full: known keys still add up, new ones are handed back.
+        batch.insert(a, wins(2, 0, 0));
+        batch.insert(c, wins(0, 0, 1));
+        hot.absorb(&mut batch, |k, v| overflow.push((k, t(v))));
+        assert!(batch.is_empty());
+        assert_eq!(overflow, [(c, (0, 0, 1))]);
+
+        let shards: Vec<_> = hot.shards.iter().map(|s| s.lock().unwrap().clone()).collect();
+        let total = |k: &Key| shards.iter().find_map(|s| s.get(k).copied()).map(t);
+        assert_eq!((total(&a), total(&b), total(&c)), (Some((3, 0, 0)), Some((0, 1, 0)), None));
+    }
+
+    #[test]
+    fn flush_writes_each_total_once_to_either_sink() {
+        let [a, b, _] = keys();
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = config::test_ingest(serde_json::json!({ "db_path": dir.path().join("db") }));
+        let datasets = Datasets::from_config(&cfg).unwrap();
+        let mut opts = rocks_cfg::tuned();
+        opts.set_merge_operator_associative("add_wins", wins_merge_op);
+        let db = dataset::open(&opts, &cfg.db_path, datasets.names()).unwrap();
+        let filled = || {
+            let hot = HotTable::new(2, 16);
+            for _ in 0..3 {
+                let mut batch = hot.batch();
+                batch.insert(a, wins(1, 0, 0));
+                batch.insert(b, wins(0, 0, 1));
+                hot.absorb(&mut batch, |_, _| panic!("table is not full"));
+            }
+            hot
+        };
+        let total = |k: Key| db.get_cf(datasets.cf(&db, 0), k.as_bytes()).unwrap().map(|v| t(GameWins::from_bytes(&v)));
+
+        let hot = filled();
+        assert_eq!(hot.flush(Sink::Db(&db, &datasets)).unwrap(), 2);
+        assert_eq!(hot.flush(Sink::Db(&db, &datasets)).unwrap(), 0);
+        assert_eq!((total(a), total(b)), (Some((3, 0, 0)), Some((0, 0, 3))));
+
+        // Bulk loads add to what is there.
+        let runs = RunWriter::new(dir.path().join("spill")).unwrap();
+        assert_eq!(filled().flush(Sink::Runs(&runs)).unwrap(), 2);
+        bulk::load(&db, &datasets, &runs.into_ssts(&opts).unwrap()).unwrap();
+        assert_eq!((total(a), total(b)), (Some((6, 0, 0)), Some((0, 0, 6))));
+
+        // A failed write is reported, not a panic.
+        let runs = RunWriter::new(dir.path().join("gone")).unwrap();
+        std::fs::remove_dir(dir.path().join("gone")).unwrap();
+        assert!(filled().flush(Sink::Runs(&runs)).is_err());
+    }
+}
@@ -1,106 +1,368 @@
-//! ingest.rs – single‑reader / multi‑worker orchestration
+//! ingest.rs – multi‑reader / multi‑worker orchestration
 //!
 //! Opens the `RocksDB` database using the *`db_path`* from `config::Ingest`, so callers
-//! no longer need to pass a `DB` handle explicitly.  The rest of the pipeline
-//! (reader thread + Rayon worker pool) is unchanged.
+//! no longer need to pass a `DB` handle explicitly.  A pool of reader threads
+//! decompresses and parses archives into one bounded channel drained by the
+//! Rayon worker pool.
 
 use anyhow::{Context, Result as AnyResult};
 use crate::GameSummary;
-use crate::chess_db::FS;
-use crate::config;
-use crate::extractor::Extractor;
+use crate::budget::Budget;
+use crate::bulk::{self, RunWriter};
+use crate::chess_db;
+use crate::config::{self, Mode};
+use crate::dataset::{self, Datasets};
+use crate::dedup::SeenSet;
+use crate::extractor::{Extractor, Filters};
 use crate::file;
+use crate::frames;
+use crate::hot::HotTable;
 use crate::merge::wins_merge_op;
+use crate::metrics;
+use crate::ndjson;
+use crate::remote::{self, Remote, RangeReader, Verified};
+use crate::replay;
 use crate::rocks_cfg;
-use crate::worker;
+use crate::worker::{self, Sink};
 use crossbeam_channel::Sender;
 use crossbeam_channel as chan;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use num_cpus;
 use rayon::ThreadPoolBuilder;
-use rocksdb::DB;
-use std::{fs, io};
-use std::sync::Arc;
+use rocksdb::{Options, DB};
+use std::{fmt, fs, io};
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 use chrono;
 
-/// Top‑level ingestion entry‑point with a single‑sender lifetime fix.
-///
-/// The *only* `Sender` is moved into the reader thread so that it is
-/// dropped automatically when the reader finishes, letting every worker
-/// see `Err(Disconnected)` and exit cleanly.  No explicit `drop(tx)` is
-/// required in the outer scope.
+/// Top‑level ingestion entry‑point: one run over every archive in
+/// `pgn_dir` and every configured URL.
 pub fn ingest(cfg: &config::Ingest) -> anyhow::Result<()> {
-    // 0) Open (or create) RocksDB once.
-    let mut db_opts = rocks_cfg::tuned();
-    db_opts.set_merge_operator_associative("add_wins", wins_merge_op);
-    let db = Arc::new(DB::open(&db_opts, &cfg.db_path)?);
-
-    // 1) Determine worker‑thread count from the system.
-    let n_threads = num_cpus::get().max(1);
-
-    // 2) Build a Rayon pool with that many threads.
-    let pool = ThreadPoolBuilder::new()
-        .num_threads(n_threads)
-        .build()
-        .context("build thread‑pool")?;
-
-    // 3) Bounded channel provides back‑pressure. Capacity scales with cache_size.
-    let channel_cap = std::cmp::max(4096, cfg.cache_size / 16);
-    let (tx, rx) = chan::bounded::<GameSummary>(channel_cap);
-
-    // 4) Spawn worker tasks inside the pool. Each has its own write‑cache.
-    pool.scope(|s| {
-        for _ in 0..n_threads {
-            let rx = rx.clone();
-            let db = db.clone();
-            let flush_threshold = cfg.cache_size;
-            s.spawn(move |_| {
-                worker::run(&rx, &db, flush_threshold);
-            });
+    let session = Session::open(cfg)?;
+    let report = session.run(sources(cfg)?)?;
+    eprintln!("{report}");
+    anyhow::ensure!(report.failures == 0, "{} archive(s) failed to read", report.failures);
+    Ok(())
+}
+
+/// Print every archive recorded in `cfg.db_path`, per dataset, oldest
+/// ingest first.
+pub fn list_files(cfg: &config::Ingest) -> anyhow::Result<()> {
+    let mut opts = rocks_cfg::tuned();
+    opts.set_merge_operator_associative("add_wins", wins_merge_op);
+    let db = dataset::open_read_only(&opts, &cfg.db_path)?;
+    for name in dataset::list(&opts, &cfg.db_path)? {
+        let cf = db.cf_handle(&name).context("dataset vanished")?;
+        let mut files = file::ingested(&db, cf)?;
+        files.sort_by_key(|(_, r)| r.ingested_at);
+        for (id, r) in files {
+            let id: String = id.iter().map(|b| format!("{b:02x}")).collect();
+            let when = chrono::DateTime::from_timestamp(r.ingested_at, 0)
+                .map_or_else(|| "?".into(), |t| t.format("%Y-%m-%d %H:%M:%S").to_string());
+            let path = if r.path.is_empty() { "(path not recorded)" } else { &r.path };
+            let partial = if r.partial { "  (partial)" } else { "" };
+            println!("{name:<16}  {id:<32}  {when}  {:>14}  {path}{partial}", r.size);
         }
+    }
+    Ok(())
+}
 
-        // 5) Reader thread: owns *the* Sender and drops it when done.
-        let reader_db = db.clone();
-        let reader_handle = std::thread::spawn({
-            let tx = tx;           // move, do not clone – guarantees closure
-            let cfg = cfg.clone();
-            move || {
-                if let Err(err) = run_reader(&cfg, &reader_db, tx) {
-                    eprintln!("[ingest] reader error: {err}");
-                }
+/// Summary printed after every run.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Archives fully parsed during the run.
+    pub archives: usize,
+    /// Archives skipped because their `FS` record already exists.
+    pub skipped: usize,
+    /// Archives (or chunks of them) that failed to read; see `ReadSummary`.
+    pub failures: usize,
+    /// Games that passed the filters and were aggregated.
+    pub games: u64,
+    /// Games skipped because they were already ingested from another archive.
+    pub duplicates: u64,
+    /// Ingest generation the database is at after this run.
+    pub generation: u64,
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.elapsed.as_secs_f64().max(f64::EPSILON);
+        write!(
+            f,
+            "[ingest] {} archive(s) ingested, {} skipped, {} failed, {} games ({} duplicates) in {:.1}s ({:.0} games/s), generation {}",
+            self.archives,
+            self.skipped,
+            self.failures,
+            self.games,
+            self.duplicates,
+            secs,
+            self.games as f64 / secs,
+            self.generation,
+        )
+    }
+}
+
+/// What the reader threads got through.
+#[derive(Debug, Default)]
+pub struct ReadSummary {
+    pub archives: usize,
+    pub skipped: usize,
+    /// Archives (or chunks of them) that failed to read.  Their games up to
+    /// the error are counted.  With dedup the archive is not recorded, so
+    /// the next run reads it again and skips those games as duplicates;
+    /// without it a retry would count them twice, so it is recorded as
+    /// `partial` instead.
+    pub failures: usize,
+    /// Finished archives, recorded by the caller once their counts are on
+    /// disk.
+    pub deferred: Vec<Arc<FileJob>>,
+}
+
+/// An open database plus everything sized from the memory budget, reused
+/// across runs (e.g. by `watch`).
+pub struct Session {
+    cfg: config::Ingest,
+    budget: Budget,
+    datasets: Datasets,
+    db_opts: Options,
+    db: Arc<DB>,
+    /// Shared by every run; exported on `metrics_addr` if set.
+    metrics: Arc<metrics::Ingest>,
+}
+
+impl Session {
+    pub fn open(cfg: &config::Ingest) -> AnyResult<Self> {
+        // 0) Size caches, queue and RocksDB from one memory budget.
+        let budget = Budget::from_config(cfg)?;
+        eprintln!(
+            "[ingest] {} workers × {} MiB cache + {} MiB prefixes, RocksDB {} MiB, queue {} games",
+            budget.threads,
+            budget.cache_bytes >> 20,
+            budget.prefix_bytes >> 20,
+            (budget.block_cache_bytes + budget.write_buffer_bytes) >> 20,
+            budget.channel_capacity,
+        );
+
+        // 1) Open (or create) RocksDB once.
+        let mut db_opts =
+            rocks_cfg::budgeted(budget.block_cache_bytes, budget.write_buffer_bytes);
+        db_opts.set_merge_operator_associative("add_wins", wins_merge_op);
+        if cfg.mode == Mode::Bulk { rocks_cfg::for_bulk_load(&mut db_opts); }
+        let datasets = Datasets::from_config(cfg)?;
+        let db = Arc::new(dataset::open(&db_opts, &cfg.db_path, datasets.names())?);
+
+        let metrics = Arc::new(metrics::Ingest::default());
+        if let Some(addr) = &cfg.metrics_addr {
+            metrics::serve_ingest(addr, metrics.clone(), db.clone(), datasets.names().to_vec())
+                .with_context(|| format!("serve metrics on {addr}"))?;
+            eprintln!("[ingest] metrics on http://{addr}/metrics");
+        }
+
+        Ok(Self { cfg: cfg.clone(), budget, datasets, db_opts, db, metrics })
+    }
+
+    /// Ingest `archives` through the reader / worker pipeline.
+    ///
+    /// The *only* `Sender` is moved into the reader thread so that it is
+    /// dropped automatically when the reader finishes, letting every worker
+    /// see `Err(Disconnected)` and exit cleanly.  No explicit `drop(tx)` is
+    /// required in the outer scope.
+    pub fn run(&self, archives: Vec<String>) -> AnyResult<Report> {
+        let (cfg, budget, datasets, db) = (&self.cfg, &self.budget, &self.datasets, &self.db);
+        let metrics = &*self.metrics;
+        let started = Instant::now();
+
+        // Let secondary instances know the counts they see may be partial,
+        // until the run ends one way or the other.
+        let generation = Generation::begin(db)?;
+
+        // Bulk loads spill sorted runs instead of writing merge operands.
+        let runs = match cfg.mode {
+            Mode::Merge => None,
+            Mode::Bulk => {
+                let dir = scratch_dir(cfg);
+                Some(RunWriter::new(&dir).with_context(|| format!("create {dir:?}"))?)
+            }
+        };
+        let sink = runs.as_ref().map_or(Sink::Db(db, datasets), Sink::Runs);
+
+        // 2) Build a Rayon pool with the budgeted number of threads.
+        let n_threads = budget.threads;
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(n_threads)
+            .build()
+            .context("build thread‑pool")?;
+
+        // 3) Bounded channel provides back‑pressure.
+        let (tx, rx) = chan::bounded::<GameSummary>(budget.channel_capacity);
+        metrics.attach_queue(rx.clone());
+
+        // 4) Spawn worker tasks inside the pool. Each has its own write‑cache;
+        //    shallow positions go to one shared table instead.
+        let hot = HotTable::new(cfg.hot_plies, budget.hot_bytes / worker::ENTRY_BYTES);
+        let seen = SeenSet::new(db, datasets, cfg.dedup, budget.seen_bytes)?;
+        let games = AtomicU64::new(0);
+        let failed = Mutex::new(None);
+        let read = pool.scope(|s| {
+            for _ in 0..n_threads {
+                let rx = rx.clone();
+                let (hot, seen, games, failed) = (&hot, &seen, &games, &failed);
+                let limits = worker::Limits {
+                    prefix_plies: cfg.prefix_plies,
+                    prefix_capacity: (budget.prefix_bytes / replay::STEP_BYTES).max(1),
+                    flush_threshold: cfg.cache_size,
+                    byte_limit: budget.cache_bytes,
+                };
+                s.spawn(move |_| {
+                    match worker::run(&rx, sink, hot, seen, limits, metrics) {
+                        Ok(n) => { games.fetch_add(n, Ordering::Relaxed); }
+                        Err(err) => {
+                            failed.lock().expect("worker error poisoned").get_or_insert(err);
+                            // Keep the reader from blocking on a full queue.
+                            for _ in rx.iter() {}
+                        }
+                    }
+                });
             }
+
+            // 5) Reader thread: owns *the* Sender and drops it when done; the
+            //    parser threads it spawns hold clones for their lifetime only.
+            let reader_db = db.clone();
+            let reader_handle = std::thread::spawn({
+                let tx = tx;           // move, do not clone – guarantees closure
+                let (cfg, datasets) = (cfg.clone(), datasets.clone());
+                move || run_reader(&cfg, &reader_db, &datasets, &archives, tx)
+            });
+
+            // Wait for the reader to finish; the pool will wait for workers.
+            reader_handle.join().expect("reader thread panicked")
         });
+        metrics.detach_queue();
+        let read = read.context("read archives")?;
+        if let Some(err) = failed.into_inner().expect("worker error poisoned") {
+            return Err(err.context("aggregate games"));
+        }
 
-        // Wait for the reader to finish; the pool will wait for workers.
-        reader_handle.join().expect("reader thread panicked");
-    });
+        // 6) Every worker has drained into the hot table – write it once.
+        metrics.operands.fetch_add(hot.flush(sink)? as u64, Ordering::Relaxed);
 
-    Ok(())
+        // 7) Bulk mode: build and ingest the SST files and compact once.
+        if let Some(runs) = runs {
+            let dir = runs.dir().to_path_buf();
+            eprintln!("[ingest] writing SST files to {dir:?}");
+            let ssts = runs.into_ssts(&self.db_opts)?;
+            eprintln!("[ingest] ingesting {} SST files and compacting", ssts.len());
+            bulk::load(db, datasets, &ssts)?;
+            let _ = fs::remove_dir(&dir);  // only if nothing else lives there
+        }
+
+        // 8) Merge operands skip the WAL: flush them so secondary instances
+        //    (and a crash) see the whole run.  Only then are the games'
+        //    fingerprints and the archives recorded, so neither ever precedes
+        //    the counts it stands for; finally mark a new generation.
+        for cf in datasets.cfs(db) { db.flush_cf(cf)?; }
+        seen.flush()?;
+        for file in &read.deferred { record_file(db, datasets, file)?; }
+        dataset::record_settings(db, datasets, cfg)?;
+        let generation = generation.finish()?;
+        metrics.archives.fetch_add(read.archives as u64, Ordering::Relaxed);
+        metrics.runs.fetch_add(1, Ordering::Relaxed);
+
+        Ok(Report {
+            archives: read.archives,
+            skipped: read.skipped,
+            failures: read.failures,
+            games: games.into_inner(),
+            duplicates: seen.duplicates(),
+            generation,
+            elapsed: started.elapsed(),
+        })
+    }
+}
+
+/// Generation started by `Session::run`.  A run that fails after writing
+/// counts still ends it on drop, so readers do not report an ingest in
+/// progress forever and drop answers cached before those writes.
+struct Generation<'a> {
+    db: &'a DB,
+    finished: bool,
+}
+
+impl<'a> Generation<'a> {
+    fn begin(db: &'a DB) -> Result<Self, rocksdb::Error> {
+        chess_db::begin_generation(db)?;
+        Ok(Self { db, finished: false })
+    }
+
+    fn finish(mut self) -> Result<u64, rocksdb::Error> {
+        self.finished = true;
+        chess_db::bump_generation(self.db)
+    }
+}
+
+impl Drop for Generation<'_> {
+    fn drop(&mut self) {
+        if self.finished { return; }
+        if let Err(err) = chess_db::bump_generation(self.db) {
+            eprintln!("[ingest] could not end the failed run's generation: {err}");
+        }
+    }
+}
+
+/// One archive being ingested, shared by all of its chunks.
+#[derive(Debug)]
+pub struct FileJob {
+    path: String,
+    file_key: Vec<u8>,
+    /// Datasets that do not have the archive yet.
+    datasets: u64,
+    /// Written under `file_key` once the archive is done.
+    record: file::Record,
+    /// Compressed size in bytes.
+    len: u64,
+    /// Set when `path` is a URL.
+    remote: Option<Remote>,
+    /// Expected SHA‑256 of the compressed bytes, from `checksum_url`.
+    sha256: Option<String>,
+    /// Chunks still being parsed; the last one to finish records the file.
+    pending: AtomicUsize,
+    /// Some chunk failed to read.
+    failed: AtomicBool,
+}
+
+/// Unit of reader work: a whole archive, or a run of its zstd frames.
+struct Unit {
+    file: Arc<FileJob>,
+    chunk: Option<frames::Chunk>,
 }
 
 /// Runs inside the *reader* thread.
 ///
-/// * `cfg.pgn_dir` – location of archives (plain PGN, `.gz`, `.zst`, …).
+/// * `datasets`    – datasets written by the run; an archive is read for
+///   those that have no `FS` record of it.
+/// * `archives`    – archives to read (PGN or Lichess NDJSON, plain, `.gz`
+///   or `.zst`), as local paths or HTTP(S) URLs.
+/// * `cfg.readers` – parser threads pulling archives (or chunks of
+///   multi‑frame `.zst` archives) off a shared queue.
 /// * `tx`            – bounded channel feeding parsed games to workers.
+///
+/// Counts of a finished archive may still sit in worker caches or the hot
+/// table, so its `FS` record is returned for the caller to write once the
+/// run's counts are on disk.  An archive that fails to read is logged and
+/// counted in `ReadSummary::failures`, so the others are still recorded;
+/// errors before any archive is read (planning, checksums) fail the call.
 pub fn run_reader(
     cfg: &config::Ingest,
     db: &DB,
+    datasets: &Datasets,
+    archives: &[String],
     tx: Sender<GameSummary>,
-) -> AnyResult<()> {
-    // 1️⃣  Total compressed bytes across all input archives ---------------
-    let archives = list_archives(&cfg.pgn_dir)?;
-    let total_bytes: u64 = archives
-        .iter()
-        .try_fold(0u64, |acc, p| {
-            let len = fs::metadata(p)
-                .with_context(|| format!("stat {p:?}"))?
-                .len();
-            Ok::<u64, anyhow::Error>(acc + len)
-        })?;
-
-    // 2️⃣  Progress bars --------------------------------------------------
+) -> AnyResult<ReadSummary> {
+    // 1️⃣  Progress bars; the total grows as archives are planned ---------
     let mp = MultiProgress::new();
-    let overall = mp.add(ProgressBar::new(total_bytes));
+    let overall = mp.add(ProgressBar::new(0));
     overall.set_style(
         ProgressStyle::with_template(
             "{spinner:.green} {bytes:>12}/{total_bytes:12} {wide_bar} {eta} {msg}",
@@ -108,85 +370,498 @@ pub fn run_reader(
         .progress_chars("▏▎▍▌▋▊▉█"),
     );
 
-    // 3️⃣  Process each archive ------------------------------------------
-    for path in &archives {
-         // ① build the RocksDB key
-        let mut file_key = Vec::from(FS);
-        file_key.extend_from_slice(&file::id(path)?);
+    // 2️⃣  Published digests for remote archives, if configured ----------
+    let agent = remote::agent();
+    let sums = match &cfg.checksum_url {
+        Some(url) => Some(remote::checksums(&agent, url)?),
+        None => None,
+    };
+
+    // 3️⃣  Plan the work, splitting multi‑frame archives between readers --
+    let (unit_tx, unit_rx) = chan::unbounded::<Unit>();
+    let mut skipped = 0;
+    for path in archives {
+         // ① build the RocksDB key from the archive's content
+        let (id, record, legacy_key, remote) = if remote::is_url(path) {
+            let remote = Remote::head(&agent, path)?;
+            let record = file::Record { path: path.clone(), size: remote.len, ..Default::default() };
+            let legacy = file::legacy_url_key(path, remote.len, remote.validator.as_deref());
+            (remote.id(&agent)?, record, Some(legacy), Some(remote))
+        } else {
+            (file::id(path)?, file::Record::local(path)?, file::legacy_key(path), None)
+        };
+        let file_key = file::key(&id);
+        let len = record.size;
 
-        // ② skip if we already saw it
-        if db.get(&file_key)?.is_some() {
+        // ② skip datasets that have it, under this identity or the old one
+        let mut todo = 0u64;
+        for (ds, cf) in datasets.cfs(db).into_iter().enumerate() {
+            if db.get_cf(cf, &file_key)?.is_some() { continue; }
+            if let Some(legacy_key) = &legacy_key {
+                if let Some(value) = db.get_cf(cf, legacy_key)? {
+                    migrate_record(db, cf, legacy_key, &file_key, &record, &value)?;
+                    continue;
+                }
+            }
+            todo |= 1 << ds;
+        }
+        if todo == 0 {
             eprintln!("Skipping already-ingested {path}");
+            skipped += 1;
             continue;
         }
 
+        let sha256 = match (&sums, &remote) {
+            (Some(sums), Some(_)) => Some(
+                sums.get(remote::file_name(path))
+                    .with_context(|| format!("{path} is not listed in the checksum file"))?
+                    .clone(),
+            ),
+            _ => None,
+        };
+        overall.inc_length(len);
+
+        // Remote archives are a single forward stream; only local ones split.
+        // Only PGN has a game boundary `frames::GameAligned` can find.
+        let chunks = if cfg.readers > 1 && remote.is_none() && is_zst(path) && !is_ndjson(path) {
+            let mut r = io::BufReader::new(
+                fs::File::open(path).with_context(|| format!("open {path:?}"))?,
+            );
+            let frames = frames::scan(&mut r)
+                .with_context(|| format!("scan zstd frames {path:?}"))?;
+            frames::chunks(&frames, cfg.readers)
+        } else {
+            Vec::new()
+        };
 
-        let short = std::path::Path::new(path)
+        let file = Arc::new(FileJob {
+            path: path.clone(),
+            file_key,
+            datasets: todo,
+            record,
+            len,
+            remote,
+            sha256,
+            pending: AtomicUsize::new(chunks.len().max(1)),
+            failed: AtomicBool::new(false),
+        });
+        if chunks.len() > 1 {
+            for chunk in chunks {
+                unit_tx.send(Unit { file: file.clone(), chunk: Some(chunk) })?;
+            }
+        } else {
+            unit_tx.send(Unit { file, chunk: None })?;
+        }
+    }
+    drop(unit_tx);
+
+    // 4️⃣  Readers drain the queue, each feeding the shared channel -------
+    let summary = std::thread::scope(|s| {
+        let handles = (0..cfg.readers.max(1))
+            .map(|_| {
+                let (unit_rx, tx) = (unit_rx.clone(), tx.clone());
+                let (mp, overall) = (&mp, &overall);
+                s.spawn(move || {
+                    let mut summary = ReadSummary::default();
+                    while let Ok(unit) = unit_rx.recv() {
+                        let file = &unit.file;
+                        if let Err(err) = read_unit(cfg, &unit, &tx, mp, overall) {
+                            eprintln!("[ingest] {}: {err:#}", file.path);
+                            summary.failures += 1;
+                            file.failed.store(true, Ordering::Release);
+                        }
+                        // The last chunk to finish decides for the archive.
+                        if file.pending.fetch_sub(1, Ordering::AcqRel) != 1 { continue; }
+                        if !file.failed.load(Ordering::Acquire) {
+                            summary.archives += 1;
+                            summary.deferred.push(file.clone());
+                        } else if !cfg.dedup {
+                            eprintln!(
+                                "[ingest] {}: recorded as partial; without dedup a retry would count its games twice",
+                                file.path,
+                            );
+                            summary.deferred.push(file.clone());
+                        }
+                    }
+                    summary
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut summary = ReadSummary { skipped, ..Default::default() };
+        for h in handles {
+            let part = h.join().expect("reader thread panicked");
+            summary.archives += part.archives;
+            summary.failures += part.failures;
+            summary.deferred.extend(part.deferred);
+        }
+        summary
+    });
+    overall.finish_and_clear();                     // leave the bar at “done”
+    mp.println("stream closed — workers finishing payloads…")?;
+
+    // 5️⃣  Done – drop the *owned* sender so the channel closes -----------
+    drop(tx);
+    Ok(summary)
+}
+
+/// Decode and parse one unit.
+fn read_unit(
+    cfg: &config::Ingest,
+    unit: &Unit,
+    tx: &Sender<GameSummary>,
+    mp: &MultiProgress,
+    overall: &ProgressBar,
+) -> AnyResult<()> {
+    let path = &unit.file.path;
+    let short = if unit.file.remote.is_some() {
+        remote::file_name(path).into()
+    } else {
+        std::path::Path::new(path)
             .file_name()
-            .map_or_else(|| path.into(), |os| os.to_string_lossy());
+            .map_or_else(|| path.into(), |os| os.to_string_lossy())
+    };
 
-        overall.set_message(short.to_string());
+    overall.set_message(short.to_string());
 
-        let file_len = fs::metadata(path)
-            .with_context(|| format!("stat {path:?}"))?
-            .len();
+    let len = match &unit.chunk {
+        Some(chunk) => chunk.bytes.end - chunk.bytes.start,
+        None => unit.file.len,
+    };
 
-        // Per‑file bar
-        let bar = mp.add(ProgressBar::new(file_len));
-        bar.set_style(
-            ProgressStyle::with_template(
-                "{spinner:.cyan} {bytes:>10}/{total_bytes:10} {wide_bar}",
-            )?
-            .progress_chars("•░▒▓█"),
-        );
+    // Per‑file (or per‑chunk) bar
+    let bar = mp.add(ProgressBar::new(len));
+    bar.set_style(
+        ProgressStyle::with_template(
+            "{spinner:.cyan} {bytes:>10}/{total_bytes:10} {wide_bar}",
+        )?
+        .progress_chars("•░▒▓█"),
+    );
 
-        // Open & wrap
-        let file = fs::File::open(path)
+    let open_at = |offset: u64| -> AnyResult<fs::File> {
+        let mut file = fs::File::open(path)
             .with_context(|| format!("open {path:?}"))?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(file)
+    };
+
+    // Set for checksummed downloads, decided once the parser reaches EOF.
+    let mut verification = None;
+    let decoder: Box<dyn io::Read + Send> = if let Some(chunk) = &unit.chunk {
+        let head = open_at(chunk.bytes.start)?.take(len);
+        let head = overall.wrap_read(bar.wrap_read(head));
+        let later = chunk.later.iter()
+            .map(|r| Ok(open_at(r.start)?.take(r.end - r.start)))
+            .collect::<AnyResult<Vec<_>>>()?;
+        Box::new(frames::GameAligned::new(head, later, chunk.first)?)
+    } else {
+        // Open (local file or resumable download) & wrap
+        let file: Box<dyn io::Read + Send> = match &unit.file.remote {
+            None => Box::new(open_at(0)?),
+            Some(r) => {
+                let body = RangeReader::new(remote::agent(), r.clone());
+                match &unit.file.sha256 {
+                    None => Box::new(body),
+                    Some(sum) => {
+                        let (body, check) = Verified::new(body, sum.clone());
+                        verification = Some(check);
+                        Box::new(body)
+                    }
+                }
+            }
+        };
         let file = bar.wrap_read(file);      // ticks file bar
         let file = overall.wrap_read(file);  // ticks global bar
         let reader = io::BufReader::new(file);
 
-        // Optional decompression
-        let decoder: Box<dyn io::Read + Send> = match std::path::Path::new(path)
+        // Optional decompression; multi‑member gzip reads to EOF so the
+        // checksum sees every byte.
+        match std::path::Path::new(&*short)
             .extension()
             .and_then(|e| e.to_str())
         {
             Some("zst" | "zstd") => Box::new(zstd::stream::read::Decoder::new(reader)?),
-            Some("gz")           => Box::new(flate2::read::GzDecoder::new(reader)),
+            Some("gz")           => Box::new(flate2::read::MultiGzDecoder::new(reader)),
             _                    => Box::new(reader),
-        };
+        }
+    };
 
-        // Parse and send games
+    // Parse and send games.  A mismatched digest surfaces at EOF, after its
+    // games were sent: the archive fails like any other read error.
+    if is_ndjson(&short) {
+        let filters = Filters::for_datasets(cfg);
+        let skipped = ndjson::read_all(io::BufReader::new(decoder), tx, &filters, unit.file.datasets)
+            .with_context(|| format!("parse {path:?}"))?;
+        if skipped > 0 { eprintln!("[ingest] {path}: skipped {skipped} malformed game(s)"); }
+    } else {
         let mut br  = pgn_reader::BufferedReader::new(decoder);
-        let mut vis = Extractor::new(&tx, cfg);
+        let mut vis = Extractor::new(tx, cfg, unit.file.datasets);
         br.read_all(&mut vis)
             .with_context(|| format!("parse {path:?}"))?;
-        bar.finish_and_clear();
-        db.put(&file_key, chrono::Utc::now().timestamp().to_be_bytes())?;
     }
-    overall.finish_and_clear();                     // leave the bar at “done”
-    mp.println("stream closed — workers finishing payloads…")?;
+    if let Some(check) = verification { check.ensure(path)?; }
+    bar.finish_and_clear();
+    Ok(())
+}
 
-    // 4️⃣  Done – drop the *owned* sender so the channel closes -----------
-    drop(tx);
+/// Mark an archive as ingested into the datasets it was read for.
+fn record_file(db: &DB, datasets: &Datasets, file: &FileJob) -> AnyResult<()> {
+    let record = file::Record {
+        ingested_at: chrono::Utc::now().timestamp(),
+        partial: file.failed.load(Ordering::Acquire),
+        ..file.record.clone()
+    };
+    let mut batch = rocksdb::WriteBatch::default();
+    for ds in dataset::indices(file.datasets) {
+        batch.put_cf(datasets.cf(db, ds), &file.file_key, record.to_bytes());
+    }
+    db.write(batch)?;
+    Ok(())
+}
+
+/// Move a record from its path‑based key to the content key, keeping the
+/// original ingest time.
+fn migrate_record(
+    db: &DB,
+    cf: &rocksdb::ColumnFamily,
+    legacy_key: &[u8],
+    file_key: &[u8],
+    record: &file::Record,
+    legacy_value: &[u8],
+) -> AnyResult<()> {
+    let ingested_at = file::Record::from_bytes(legacy_value).ingested_at;
+    let mut batch = rocksdb::WriteBatch::default();
+    batch.put_cf(cf, file_key, file::Record { ingested_at, ..record.clone() }.to_bytes());
+    batch.delete_cf(cf, legacy_key);
+    db.write(batch)?;
     Ok(())
 }
 
-/// Return every regular file inside `dir` whose name ends with `.pgn.zst`,
-/// sorted lexicographically so the ingest order is deterministic.
-fn list_archives(dir: &str) -> anyhow::Result<Vec<String>> {
+/// Where bulk runs and SST files are written.
+fn scratch_dir(cfg: &config::Ingest) -> String {
+    cfg.spill_dir.clone().unwrap_or_else(|| format!("{}.bulk", cfg.db_path))
+}
+
+fn is_zst(path: &str) -> bool {
+    path.ends_with(".zst") || path.ends_with(".zstd")
+}
+
+/// Lichess API export, optionally compressed.
+fn is_ndjson(path: &str) -> bool {
+    [".ndjson", ".ndjson.zst", ".ndjson.gz"].iter().any(|ext| path.ends_with(ext))
+}
+
+/// Local archives from `pgn_dir` (when set) followed by `urls`.
+pub fn sources(cfg: &config::Ingest) -> anyhow::Result<Vec<String>> {
+    let mut v = if cfg.pgn_dir.is_empty() { Vec::new() } else { list_archives(&cfg.pgn_dir)? };
+    v.extend(cfg.urls.iter().cloned());
+    Ok(v)
+}
+
+/// Return every regular file inside `dir` that `is_archive`, sorted
+/// lexicographically so the ingest order is deterministic.
+pub fn list_archives(dir: &str) -> anyhow::Result<Vec<String>> {
     let mut v = std::fs::read_dir(dir)
         .with_context(|| format!("read_dir {dir}"))?
         .filter_map(std::result::Result::ok)
         .map(|e| e.path())
-        .filter(|p| p.is_file() &&
-                   p.file_name()
-                     .and_then(|n| n.to_str())
-                     .map_or(false, |n| n.ends_with(".pgn.zst")))
+        .filter(|p| p.is_file() && is_archive(p))
         .map(|p| p.to_string_lossy().into_owned())
         .collect::<Vec<_>>();
 
     v.sort();        // stable, predictable run-to-run order
     Ok(v)
 }
+
+/// Whether `path` names an archive `ingest` knows how to read.
+#[must_use] pub fn is_archive(path: &std::path::Path) -> bool {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|n| n.ends_with(".pgn.zst") || is_ndjson(n))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chess_db::{pos_to_keyable, ChessDB};
+    use crate::config::TestArchives;
+    use shakmaty::Chess;
+
+    const GAMES: &str = "[Event \"Rated Blitz game\"]\n[Site \"https://lichess.org/aaaaaaaa\"]\n[Result \"1-0\"]\n\n\
+        1. e4 e5 2. Qh5 Nc6 3. Bc4 Nf6 4. Qxf7# 1-0\n\n\
+        [Event \"Rated Blitz game\"]\n[Site \"https://lichess.org/bbbbbbbb\"]\n[Result \"0-1\"]\n\n\
+        1. d4 e5 0-1\n\n";
+
+    #[test]
+    fn bulk_mode_records_good_archives_when_another_fails() {
+        let t = TestArchives::new(serde_json::json!({ "mode": "bulk", "dedup": true }));
+        t.add("a.pgn.zst", GAMES);
+        t.add_bytes("b.pgn.zst", b"not a zstd frame");
+        let (cfg, session) = (&t.cfg, t.session());
+
+        let report = session.run(sources(cfg).unwrap()).unwrap();
+        assert_eq!((report.archives, report.failures, report.games), (1, 1, 2));
+
+        // Only the broken archive is read again, so nothing is counted twice.
+        let report = session.run(sources(cfg).unwrap()).unwrap();
+        assert_eq!((report.archives, report.skipped, report.failures), (0, 1, 1));
+        let cf = session.db.cf_handle(&cfg.dataset).unwrap();
+        assert_eq!(file::ingested(&session.db, cf).unwrap().len(), 1);
+        let start = ChessDB::new(&session.db, cf)
+            .get_pos_wins(&pos_to_keyable(&Chess::default()))
+            .unwrap()
+            .unwrap();
+        assert_eq!((start.white, start.black, start.draws), (1, 1, 0));
+    }
+
+    #[test]
+    fn hot_table_keeps_totals_and_saves_operands() {
+        let games: String = (0..2000)
+            .map(|i| {
+                let (moves, result) = if i % 3 == 0 { ("1. e4 e5 2. Nf3 Nc6 3. Bb5 a6", "1-0") } else { ("1. d4 d5 2. c4 e6 3. Nc3 Nf6", "1/2-1/2") };
+                format!("[Event \"Rated Blitz game\"]\n[Site \"https://lichess.org/g{i:07}\"]\n[Result \"{result}\"]\n\n{moves} {result}\n\n")
+            })
+            .collect();
+        let run = |hot_plies: usize| {
+            let t = TestArchives::new(serde_json::json!({ "hot_plies": hot_plies, "cache_size": 4 }));
+            t.add("a.pgn.zst", &games);
+            let (cfg, session) = (&t.cfg, t.session());
+            assert_eq!(session.run(sources(cfg).unwrap()).unwrap().games, 2000);
+            let cf = session.db.cf_handle(&cfg.dataset).unwrap();
+            let stats = ChessDB::new(&session.db, cf).get_pos_stats(&Chess::default()).unwrap().unwrap();
+            let mut moves: Vec<_> = stats.game_moves.iter()
+                .map(|(m, w)| (m.clone(), (w.white, w.black, w.draws)))
+                .collect();
+            moves.sort();
+            let w = stats.game_wins;
+            ((w.white, w.black, w.draws), moves, session.metrics.operands.load(Ordering::Relaxed))
+        };
+
+        let (cold, hot) = (run(0), run(6));
+        assert_eq!(cold.0, (667, 0, 1333));
+        assert_eq!((&hot.0, &hot.1), (&cold.0, &cold.1));
+        // Shallow keys are written once instead of once per worker flush.
+        assert!(hot.2 * 10 < cold.2, "{} operands with the hot table, {} without", hot.2, cold.2);
+    }
+
+    #[test]
+    fn failed_runs_end_their_generation() {
+        let t = TestArchives::new(serde_json::json!({}));
+        let session = t.session();
+        let missing = t.dir.path().join("pgn/missing.pgn.zst").to_string_lossy().into_owned();
+        assert!(session.run(vec![missing]).is_err());
+        assert!(!chess_db::ingesting(&session.db));
+        assert_eq!(chess_db::generation(&session.db), 1);
+    }
+
+    #[test]
+    fn merge_mode_counts_every_dataset() {
+        let t = TestArchives::new(serde_json::json!({
+            "datasets": [{ "name": "long", "min_ply_count": 4 }],
+        }));
+        t.add("a.pgn.zst", GAMES);
+        let (cfg, session) = (&t.cfg, t.session());
+        let report = session.run(sources(cfg).unwrap()).unwrap();
+        assert_eq!((report.archives, report.games), (1, 2));
+
+        // Read back the way the server opens the database.
+        let mut opts = rocks_cfg::tuned();
+        opts.set_merge_operator_associative("add_wins", wins_merge_op);
+        let db = dataset::open_read_only(&opts, &cfg.db_path).unwrap();
+        let start = |name: &str| {
+            ChessDB::new(&db, db.cf_handle(name).unwrap())
+                .get_pos_stats(&Chess::default())
+                .unwrap()
+                .unwrap()
+        };
+        let all = start(&cfg.dataset);
+        assert_eq!((all.game_wins.white, all.game_wins.black, all.game_moves.len()), (1, 1, 2));
+        let long = start("long");
+        assert_eq!((long.game_wins.white, long.game_wins.black, long.game_moves.len()), (1, 0, 1));
+    }
+
+    #[test]
+    fn failed_archives_are_retried_only_with_dedup() {
+        // Games up to the broken end are read and counted.
+        let games: String = (0..5000)
+            .map(|i| format!("[Event \"Rated Blitz game\"]\n[Site \"https://lichess.org/g{i:07}\"]\n[Result \"1-0\"]\n\n1. e4 e5 1-0\n\n"))
+            .collect();
+        let mut broken = zstd::encode_all(games.as_bytes(), 3).unwrap();
+        broken.extend_from_slice(b"not a zstd frame");
+
+        for dedup in [true, false] {
+            let t = TestArchives::new(serde_json::json!({ "dedup": dedup }));
+            t.add_bytes("a.pgn.zst", &broken);
+            let (cfg, session) = (&t.cfg, t.session());
+            let cf = session.db.cf_handle(&cfg.dataset).unwrap();
+            let white = || {
+                ChessDB::new(&session.db, cf)
+                    .get_pos_wins(&pos_to_keyable(&Chess::default()))
+                    .unwrap()
+                    .map_or(0, |w| w.white)
+            };
+
+            let first = session.run(sources(cfg).unwrap()).unwrap();
+            assert_eq!((first.archives, first.failures), (0, 1));
+            assert!(first.games > 0);
+            let counted = white();
+            assert_eq!(u64::from(counted), first.games);
+
+            let again = session.run(sources(cfg).unwrap()).unwrap();
+            let records = file::ingested(&session.db, cf).unwrap();
+            if dedup {
+                // Read again, but every game it gets to is a duplicate.
+                assert_eq!((again.failures, again.games, again.duplicates), (1, 0, first.games));
+                assert!(records.is_empty());
+            } else {
+                assert_eq!((again.skipped, again.failures, again.games), (1, 0, 0));
+                assert!(records.len() == 1 && records[0].1.partial);
+            }
+            assert_eq!(white(), counted);
+        }
+    }
+
+    #[test]
+    fn legacy_records_move_to_the_content_key() {
+        let t = TestArchives::new(serde_json::json!({}));
+        let path = t.add("a.pgn.zst", GAMES);
+        let (cfg, session) = (&t.cfg, t.session());
+        let cf = session.db.cf_handle(&cfg.dataset).unwrap();
+        let legacy = file::legacy_key(&path).unwrap();
+        session.db.put_cf(cf, &legacy, 1_600_000_000i64.to_be_bytes()).unwrap();
+
+        let report = session.run(vec![path.clone()]).unwrap();
+        assert_eq!((report.archives, report.skipped, report.games), (0, 1, 0));
+        assert!(session.db.get_cf(cf, &legacy).unwrap().is_none());
+        let records = file::ingested(&session.db, cf).unwrap();
+        assert_eq!(records.len(), 1);
+        let (id, record) = &records[0];
+        assert_eq!(id.as_slice(), file::id(&path).unwrap());
+        assert_eq!((record.path.as_str(), record.ingested_at), (path.as_str(), 1_600_000_000));
+    }
+
+    #[test]
+    fn games_repeated_across_archives_count_once() {
+        // The second archive repeats the first's opening game with other tags.
+        let (first, _) = GAMES.split_at(GAMES.find("\n\n[Event").unwrap() + 2);
+        let again = first.replace("[Result", "[Round \"1\"]\n[Result");
+        let t = TestArchives::new(serde_json::json!({ "dedup": true }));
+        t.add("a.pgn.zst", GAMES);
+        t.add("b.pgn.zst", &again);
+        let (cfg, session) = (&t.cfg, t.session());
+
+        let report = session.run(sources(cfg).unwrap()).unwrap();
+        assert_eq!((report.archives, report.games, report.duplicates), (2, 2, 1));
+
+        // Fingerprints persist: a later archive with the same game adds nothing.
+        let c = t.add("c.pgn.zst", &format!("{again}\n"));
+        let report = session.run(vec![c]).unwrap();
+        assert_eq!((report.archives, report.games, report.duplicates), (1, 0, 1));
+
+        let cf = session.db.cf_handle(&cfg.dataset).unwrap();
+        let start = ChessDB::new(&session.db, cf)
+            .get_pos_wins(&pos_to_keyable(&Chess::default()))
+            .unwrap()
+            .unwrap();
+        assert_eq!((start.white, start.black), (1, 1));
+    }
+}
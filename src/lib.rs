@@ -9,17 +9,33 @@ extern crate shakmaty;
 extern crate sysinfo;
 extern crate zstd;
 
+pub mod api_error;
+pub mod budget;
+pub mod bulk;
+pub mod cache;
 pub mod chess_db;
 pub mod config;
+pub mod dataset;
+pub mod dedup;
 pub mod extractor;
 pub mod file;
+pub mod frames;
 pub mod game_stats;
+pub mod hot;
 pub mod ingest;
 pub mod merge;
+pub mod metrics;
+pub mod ndjson;
+pub mod openings;
+pub mod rate_limit;
+pub mod remote;
+pub mod replay;
 pub mod rocks_cfg;
 pub mod server;
+pub mod watch;
 pub mod worker;
 
+use game_stats::GameWins;
 use shakmaty::{Color, san::SanPlus};
 use serde::Serialize;
 
@@ -29,6 +45,11 @@ use serde::Serialize;
 pub struct GameSummary {
     pub winner: Option<Color>,
     pub sans: Vec<SanPlus>,
+    /// Identifies the game across archives, see `dedup`.
+    pub fingerprint: dedup::Fingerprint,
+    /// Bit `i` is set if the game passed the filters of dataset `i`, see
+    /// `dataset::Datasets`.
+    pub datasets: u64,
 }
 
 #[derive(Serialize)]
@@ -38,6 +59,8 @@ pub struct MoveResult {
     white: u32,
     black: u32,
     draws: u32,
+    #[serde(flatten)]
+    rates: Rates,
 }
 
 #[derive(Serialize)]
@@ -45,5 +68,38 @@ pub struct PositionResult {
     white: u32,
     black: u32,
     draws: u32,
+    #[serde(flatten)]
+    rates: Rates,
     moves: Vec<MoveResult>,
 }
+
+/// Statistics derived from a `GameWins`, so clients need not compute them.
+/// Scores are for the side to move, from 0 (always lost) to 1.
+#[derive(Serialize)]
+pub struct Rates {
+    total: u32,
+    white_pct: f64,
+    draws_pct: f64,
+    black_pct: f64,
+    score: Option<f64>,
+    /// 95 % Wilson interval of `score`.
+    score_low: Option<f64>,
+    score_high: Option<f64>,
+}
+
+impl Rates {
+    #[must_use] pub fn new(wins: &GameWins, turn: Color) -> Self {
+        let total = wins.total();
+        let pct = |n: u32| if total == 0 { 0.0 } else { 100.0 * f64::from(n) / f64::from(total) };
+        let interval = wins.score_interval(turn);
+        Self {
+            total,
+            white_pct: pct(wins.white),
+            draws_pct: pct(wins.draws),
+            black_pct: pct(wins.black),
+            score: wins.score(turn),
+            score_low: interval.map(|(low, _)| low),
+            score_high: interval.map(|(_, high)| high),
+        }
+    }
+}
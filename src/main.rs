@@ -1,10 +1,11 @@
 use std::{fs, path::PathBuf};
 
 use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use chess_aggregator::ingest;
 use chess_aggregator::server;
 use chess_aggregator::config;
+use chess_aggregator::watch;
 
 /// Command‑line entry point. Replaces manual `std::env::args()` handling
 /// with `clap` – easier to extend and gives free `--help`.
@@ -22,31 +23,92 @@ enum Command {
         /// Path to the JSON file
         #[arg(value_name = "CONFIG.json")]
         config: PathBuf,
+        /// Keep running and ingest new archives as they appear in `pgn_dir`
+        #[arg(long)]
+        watch: bool,
+    },
+    /// List the archives already ingested into the configured database
+    Files {
+        /// Path to the ingest JSON file
+        #[arg(value_name = "CONFIG.json")]
+        config: PathBuf,
     },
     /// Launch the HTTP API backed by an existing RocksDB database
     Serve {
         /// Path to the JSON file
         #[arg(value_name = "CONFIG.json")]
         config: PathBuf,
+        #[command(flatten)]
+        net: ServeFlags,
     },
 }
 
+/// Network settings of `serve`; each flag overrides the JSON config.
+#[derive(Args)]
+struct ServeFlags {
+    /// Address to listen on; repeat for several
+    #[arg(long = "bind", value_name = "ADDR")]
+    bind: Vec<String>,
+    #[arg(long)]
+    port: Option<u16>,
+    /// HTTP worker threads
+    #[arg(long)]
+    workers: Option<usize>,
+    /// Seconds before a request is answered with 503
+    #[arg(long, value_name = "SECS")]
+    request_timeout: Option<u64>,
+    /// Origin allowed to call the API from a browser (`*` for any); repeat for several
+    #[arg(long = "cors-origin", value_name = "ORIGIN")]
+    cors_origins: Vec<String>,
+    /// PEM certificate chain; serves HTTPS together with --tls-key
+    #[arg(long, value_name = "PEM", requires = "tls_key")]
+    tls_cert: Option<String>,
+    /// PEM private key of --tls-cert
+    #[arg(long, value_name = "PEM", requires = "tls_cert")]
+    tls_key: Option<String>,
+}
+
+impl ServeFlags {
+    fn apply(self, cfg: &mut config::Server) {
+        if !self.bind.is_empty() { cfg.bind = self.bind; }
+        if let Some(port) = self.port { cfg.port = port; }
+        if self.workers.is_some() { cfg.workers = self.workers; }
+        if let Some(secs) = self.request_timeout { cfg.request_timeout_secs = secs; }
+        if !self.cors_origins.is_empty() { cfg.cors_origins = self.cors_origins; }
+        if let (Some(cert_path), Some(key_path)) = (self.tls_cert, self.tls_key) {
+            cfg.tls = Some(config::Tls { cert_path, key_path });
+        }
+    }
+}
+
 fn main() -> Result<()> {
     let cli = Cli::parse();
 
     match cli.command {
-        Command::Ingest { config } => {
+        Command::Ingest { config, watch } => {
+            let bytes = fs::read(&config)
+                .with_context(|| format!("reading {:?}", config))?;
+            let cfg: config::Ingest = serde_json::from_slice(&bytes)
+                .context("parsing JSON config")?;
+            if watch {
+                watch::watch(&cfg)?;
+            } else {
+                ingest::ingest(&cfg)?;
+            }
+        }
+        Command::Files { config } => {
             let bytes = fs::read(&config)
                 .with_context(|| format!("reading {:?}", config))?;
             let cfg: config::Ingest = serde_json::from_slice(&bytes)
                 .context("parsing JSON config")?;
-            ingest::ingest(&cfg)?;
+            ingest::list_files(&cfg)?;
         }
-        Command::Serve { config } => {
+        Command::Serve { config, net } => {
             let bytes = fs::read(&config)
                 .with_context(|| format!("reading {:?}", config))?;
-            let cfg: config::Server = serde_json::from_slice(&bytes)
+            let mut cfg: config::Server = serde_json::from_slice(&bytes)
                 .context("parsing JSON config")?;
+            net.apply(&mut cfg);
             server::serve(cfg)?;
         }
     }
@@ -0,0 +1,255 @@
+//! metrics.rs – Prometheus metrics for the server and long ingests.
+//!
+//! Both sides render the plain‑text exposition format on demand from
+//! atomics they already keep, plus a handful of `RocksDB` properties, so
+//! there is no registry and nothing to update on the hot paths beyond a
+//! counter increment.  `serve` exposes them at `/metrics`; an ingest with
+//! `metrics_addr` set answers every request on that address with them.
+
+use crate::GameSummary;
+use crossbeam_channel::Receiver;
+use rocksdb::DB;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Upper bounds (seconds) of the request latency buckets.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.000_5, 0.001, 0.002_5, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0,
+];
+
+/// `RocksDB` integer properties exported per dataset.
+const CF_PROPERTIES: [(&str, &str, &str); 4] = [
+    ("rocksdb.total-sst-files-size", "rocksdb_sst_bytes", "Size of all SST files."),
+    ("rocksdb.estimate-pending-compaction-bytes", "rocksdb_pending_compaction_bytes", "Bytes compaction still has to rewrite."),
+    ("rocksdb.estimate-num-keys", "rocksdb_keys", "Estimated number of keys."),
+    ("rocksdb.cur-size-all-mem-tables", "rocksdb_memtable_bytes", "Size of the memtables."),
+];
+
+/// Text in the Prometheus exposition format.
+#[derive(Default)]
+pub struct Exposition(String);
+
+impl Exposition {
+    /// Start metric family `name`; `kind` is `counter`, `gauge` or
+    /// `histogram`.
+    pub fn family(&mut self, name: &str, kind: &str, help: &str) -> &mut Self {
+        let _ = writeln!(self.0, "# HELP {name} {help}\n# TYPE {name} {kind}");
+        self
+    }
+
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Into<f64>) -> &mut Self {
+        let _ = writeln!(self.0, "{name}{} {}", render_labels(labels), value.into());
+        self
+    }
+
+    #[must_use] pub fn finish(self) -> String { self.0 }
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() { return String::new(); }
+    let pairs: Vec<String> = labels.iter()
+        .map(|(k, v)| format!("{k}=\"{}\"", v.replace('\\', "\\\\").replace('"', "\\\"")))
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+/// Latency histogram of one endpoint.
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, secs: f64) {
+        for (bucket, le) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if secs <= le { *bucket += 1; }
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+
+    fn render(&self, out: &mut Exposition, name: &str, endpoint: &str) {
+        for (n, le) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            let le = le.to_string();
+            out.sample(&format!("{name}_bucket"), &[("endpoint", endpoint), ("le", &le)], *n as f64);
+        }
+        out.sample(&format!("{name}_bucket"), &[("endpoint", endpoint), ("le", "+Inf")], self.count as f64)
+            .sample(&format!("{name}_sum"), &[("endpoint", endpoint)], self.sum)
+            .sample(&format!("{name}_count"), &[("endpoint", endpoint)], self.count as f64);
+    }
+}
+
+/// Request counts and latencies of the HTTP API.
+#[derive(Default)]
+pub struct Requests {
+    /// Responses by endpoint pattern and status code.
+    counts: Mutex<BTreeMap<(String, u16), u64>>,
+    latency: Mutex<BTreeMap<String, Histogram>>,
+}
+
+impl Requests {
+    pub fn observe(&self, endpoint: &str, status: u16, elapsed: Duration) {
+        *self.counts.lock().expect("metrics poisoned")
+            .entry((endpoint.to_owned(), status))
+            .or_default() += 1;
+        self.latency.lock().expect("metrics poisoned")
+            .entry(endpoint.to_owned())
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn render(&self, out: &mut Exposition) {
+        out.family("chess_api_requests_total", "counter", "HTTP responses by endpoint and status.");
+        for ((endpoint, status), n) in self.counts.lock().expect("metrics poisoned").iter() {
+            let status = status.to_string();
+            out.sample("chess_api_requests_total", &[("endpoint", endpoint), ("status", &status)], *n as f64);
+        }
+        let name = "chess_api_request_duration_seconds";
+        out.family(name, "histogram", "Time to answer a request.");
+        for (endpoint, h) in self.latency.lock().expect("metrics poisoned").iter() {
+            h.render(out, name, endpoint);
+        }
+    }
+}
+
+/// Size and backlog of every dataset plus the shared block cache.
+pub fn render_rocksdb(out: &mut Exposition, db: &DB, datasets: &[String]) {
+    for (property, name, help) in CF_PROPERTIES {
+        out.family(name, "gauge", help);
+        for ds in datasets {
+            let Some(cf) = db.cf_handle(ds) else { continue };
+            if let Ok(Some(v)) = db.property_int_value_cf(cf, property) {
+                out.sample(name, &[("dataset", ds)], v as f64);
+            }
+        }
+    }
+    // One cache serves every column family.
+    if let Ok(Some(v)) = db.property_int_value("rocksdb.block-cache-usage") {
+        out.family("rocksdb_block_cache_bytes", "gauge", "Bytes held by the block cache.")
+            .sample("rocksdb_block_cache_bytes", &[], v as f64);
+    }
+}
+
+/// Progress of the ingest runs of one process.
+#[derive(Default)]
+pub struct Ingest {
+    pub games: AtomicU64,
+    pub duplicates: AtomicU64,
+    /// Worker cache flushes to the sink.
+    pub flushes: AtomicU64,
+    /// Totals written by those flushes and the hot table: merge operands,
+    /// or run records in bulk mode.
+    pub operands: AtomicU64,
+    pub archives: AtomicU64,
+    pub runs: AtomicU64,
+    /// Channel between readers and workers of the current run.
+    queue: Mutex<Option<Receiver<GameSummary>>>,
+}
+
+impl Ingest {
+    /// Report the depth of `rx` until `detach_queue`.
+    pub fn attach_queue(&self, rx: Receiver<GameSummary>) {
+        *self.queue.lock().expect("metrics poisoned") = Some(rx);
+    }
+
+    pub fn detach_queue(&self) {
+        *self.queue.lock().expect("metrics poisoned") = None;
+    }
+
+    pub fn render(&self, out: &mut Exposition) {
+        let counters = [
+            ("chess_ingest_games_total", "Games aggregated.", &self.games),
+            ("chess_ingest_duplicates_total", "Games skipped as already ingested.", &self.duplicates),
+            ("chess_ingest_flushes_total", "Worker cache flushes.", &self.flushes),
+            ("chess_ingest_operands_total", "Totals written to RocksDB or sorted runs.", &self.operands),
+            ("chess_ingest_archives_total", "Archives ingested.", &self.archives),
+            ("chess_ingest_runs_total", "Completed ingest runs.", &self.runs),
+        ];
+        for (name, help, value) in counters {
+            out.family(name, "counter", help)
+                .sample(name, &[], value.load(Ordering::Relaxed) as f64);
+        }
+        let (depth, capacity) = self.queue.lock().expect("metrics poisoned")
+            .as_ref()
+            .map_or((0, 0), |rx| (rx.len(), rx.capacity().unwrap_or(0)));
+        out.family("chess_ingest_queue_depth", "gauge", "Games waiting for a worker.")
+            .sample("chess_ingest_queue_depth", &[], depth as f64)
+            .family("chess_ingest_queue_capacity", "gauge", "Games the queue holds when full.")
+            .sample("chess_ingest_queue_capacity", &[], capacity as f64);
+    }
+}
+
+/// How long one client may take to send its request or read the answer;
+/// connections are served one at a time, so an idle one would block scrapes.
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Answer every HTTP request on `addr` with `render()`, from a background
+/// thread; enough for a scraper, no routing.
+pub fn serve_plain(addr: &str, render: impl Fn() -> String + Send + 'static) -> std::io::Result<()> {
+    answer_all(TcpListener::bind(addr)?, render);
+    Ok(())
+}
+
+fn answer_all(listener: TcpListener, render: impl Fn() -> String + Send + 'static) {
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if stream.set_read_timeout(Some(SCRAPE_TIMEOUT)).is_err()
+                || stream.set_write_timeout(Some(SCRAPE_TIMEOUT)).is_err()
+            {
+                continue;
+            }
+            let mut reader = BufReader::new(&stream);
+            // Skip the request line and headers.
+            let mut line = String::new();
+            while reader.read_line(&mut line).is_ok_and(|n| n > 2) { line.clear(); }
+            let body = render();
+            let _ = write!(
+                &stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len(),
+            );
+        }
+    });
+}
+
+/// Ingest metrics plus `RocksDB` properties of `db`, served on `addr`.
+pub fn serve_ingest(addr: &str, metrics: Arc<Ingest>, db: Arc<DB>, datasets: Vec<String>) -> std::io::Result<()> {
+    serve_plain(addr, move || {
+        let mut out = Exposition::default();
+        metrics.render(&mut out);
+        render_rocksdb(&mut out, &db, &datasets);
+        out.finish()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpStream;
+    use std::time::Instant;
+
+    #[test]
+    fn idle_clients_do_not_block_scrapes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        answer_all(listener, || "up 1\n".to_owned());
+
+        // Connects and never sends a request.
+        let _idle = TcpStream::connect(addr).unwrap();
+        let started = Instant::now();
+        let mut scrape = TcpStream::connect(addr).unwrap();
+        scrape.write_all(b"GET /metrics HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        let mut answer = String::new();
+        scrape.read_to_string(&mut answer).unwrap();
+        assert!(answer.starts_with("HTTP/1.1 200 OK") && answer.ends_with("up 1\n"));
+        assert!(started.elapsed() < SCRAPE_TIMEOUT * 2);
+    }
+}
@@ -0,0 +1,169 @@
+//! ndjson.rs – Lichess API game exports, one JSON object per line.
+//!
+//! The counterpart of `Extractor` for `/api/games/user` style exports:
+//! `moves` is a space‑separated SAN string and the headers `Extractor`
+//! filters on live in `rated`, `speed` and `players.*.rating`.  Games are
+//! turned into the same `GameSummary` and pass through the same `Filters`.
+
+use crate::extractor::Filters;
+use crate::{dataset, dedup, GameSummary};
+use crossbeam_channel::Sender;
+use serde::Deserialize;
+use shakmaty::{san::SanPlus, Color};
+use std::io::{self, BufRead};
+
+#[derive(Deserialize)]
+struct Game {
+    id: String,
+    #[serde(default)]
+    rated: bool,
+    #[serde(default)]
+    variant: Option<String>,
+    #[serde(default)]
+    speed: String,
+    #[serde(default)]
+    status: String,
+    #[serde(default)]
+    winner: Option<String>,
+    #[serde(default)]
+    moves: String,
+    players: Players,
+}
+
+#[derive(Deserialize)]
+struct Players {
+    white: Player,
+    black: Player,
+}
+
+#[derive(Deserialize)]
+struct Player {
+    /// Missing for games against the computer.
+    rating: Option<u32>,
+}
+
+impl Game {
+    /// Same checks `Extractor` applies to the PGN headers.  The event is
+    /// rebuilt the way Lichess names it in PGN, e.g. `Rated Blitz game`.
+    fn passes(&self, filters: &Filters) -> bool {
+        let standard = matches!(self.variant.as_deref(), None | Some("standard"));
+        // Only games that ended on the board; `started` and `unknownFinish`
+        // have no result and would count as draws.
+        let finished = matches!(
+            self.status.as_str(),
+            "mate"
+                | "resign"
+                | "stalemate"
+                | "timeout"
+                | "draw"
+                | "outoftime"
+                | "cheat"
+                | "variantEnd"
+                | "insufficientMaterialClaim"
+        );
+        let event = format!("{} {} game", if self.rated { "Rated" } else { "Casual" }, self.speed);
+        let rating = |r: Option<u32>| r.map_or(filters.allows_unrated(), |r| filters.rating(Some(r)));
+        standard
+            && finished
+            && filters.event(&event)
+            && rating(self.players.white.rating)
+            && rating(self.players.black.rating)
+    }
+
+    fn winner(&self) -> Option<Color> {
+        match self.winner.as_deref() {
+            Some("white") => Some(Color::White),
+            Some("black") => Some(Color::Black),
+            _ => None,
+        }
+    }
+}
+
+/// Parse every line of `reader` and send the games that pass the filters of
+/// at least one dataset in `datasets` (indexes into `filters`).
+///
+/// Like a broken game in a PGN archive, a malformed line or one with bad
+/// SAN is skipped rather than failing the archive halfway through; the
+/// number skipped is returned.  Only read errors are fatal.
+pub fn read_all<R: BufRead>(
+    mut reader: R,
+    tx: &Sender<GameSummary>,
+    filters: &[Filters],
+    datasets: u64,
+) -> io::Result<u64> {
+    let mut line = Vec::new();
+    let mut skipped = 0;
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 { break; }
+        if line.trim_ascii().is_empty() { continue; }
+
+        let Ok(game) = serde_json::from_slice::<Game>(&line) else {
+            skipped += 1;
+            continue;
+        };
+        let passing = dataset::retain(datasets, |ds| game.passes(&filters[usize::from(ds)]));
+        if passing == 0 { continue; }
+
+        let Ok(sans) = game
+            .moves
+            .split_ascii_whitespace()
+            .map(str::parse::<SanPlus>)
+            .collect::<Result<Vec<_>, _>>()
+        else {
+            skipped += 1;
+            continue;
+        };
+        let plies = sans.len() as u32;
+        let datasets = dataset::retain(passing, |ds| filters[usize::from(ds)].plies(plies));
+        if datasets == 0 { continue; }
+
+        // same fingerprint as the `Site` header of Lichess' PGN dumps
+        let fingerprint = dedup::by_id(&format!("https://lichess.org/{}", game.id));
+        let summary = GameSummary { winner: game.winner(), sans, fingerprint, datasets };
+        let _ = tx.send(summary); // ignore error on shutdown
+    }
+    Ok(skipped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    #[test]
+    fn filters_like_pgn() {
+        let cfg = |min_rating: u32| config::test_ingest(serde_json::json!({
+            "min_ply_count": 4, "time_controls": ["blitz"], "min_rating": min_rating,
+        }));
+        let input = [
+            // kept
+            r#"{"id":"x","rated":true,"variant":"standard","speed":"blitz","status":"mate","winner":"white","moves":"e4 e5 Bc4 Nc6 Qh5 Nf6 Qxf7#","players":{"white":{"rating":1600},"black":{"rating":1550}}}"#,
+            // casual
+            r#"{"id":"x","rated":false,"speed":"blitz","status":"draw","moves":"d4 d5 c4 e6","players":{"white":{"rating":1600},"black":{"rating":1600}}}"#,
+            // wrong speed
+            r#"{"id":"x","rated":true,"speed":"bullet","status":"draw","moves":"d4 d5 c4 e6","players":{"white":{"rating":1600},"black":{"rating":1600}}}"#,
+            // still being played
+            r#"{"id":"x","rated":true,"speed":"blitz","status":"started","moves":"d4 d5 c4 e6","players":{"white":{"rating":1600},"black":{"rating":1600}}}"#,
+            // kept: a draw claimed on insufficient material
+            r#"{"id":"x","rated":true,"speed":"blitz","status":"insufficientMaterialClaim","moves":"d4 d5 c4 e6","players":{"white":{"rating":1600},"black":{"rating":1600}}}"#,
+            // computer opponent has no rating: kept only without a minimum
+            r#"{"id":"x","rated":true,"speed":"blitz","status":"draw","moves":"d4 d5 c4 e6","players":{"white":{"rating":1600},"black":{"aiLevel":3}}}"#,
+            "",
+            // truncated line, then moves that are not SAN
+            r#"{"id":"x","rated":true,"speed":"blitz","#,
+            r#"{"id":"x","rated":true,"speed":"blitz","status":"draw","moves":"d4 d5 c4 Zz9","players":{"white":{"rating":1600},"black":{"rating":1600}}}"#,
+            // too short
+            r#"{"id":"x","rated":true,"speed":"blitz","status":"resign","winner":"black","moves":"f3 e5","players":{"white":{"rating":1600},"black":{"rating":1600}}}"#,
+        ]
+        .join("\n");
+
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let filters = [Filters::new(&cfg(1500)), Filters::new(&cfg(0))];
+        assert_eq!(read_all(input.as_bytes(), &tx, &filters, 0b11).unwrap(), 2);
+        drop(tx);
+
+        let games: Vec<_> = rx.iter().map(|g| (g.winner, g.sans.len(), g.datasets)).collect();
+        assert_eq!(games, [(Some(Color::White), 7, 0b11), (None, 4, 0b11), (None, 4, 0b10)]);
+    }
+}
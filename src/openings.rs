@@ -0,0 +1,117 @@
+//! openings.rs – ECO codes and names for the `opening` of `/lichess`.
+//!
+//! Games are aggregated without their headers, so names come from a table
+//! in the format of `lichess-org/chess-openings`: tab‑separated `eco`,
+//! `name` and `pgn` columns, one named line per row.  Like Lichess, a
+//! position is named after the deepest named position of the line that
+//! reached it.
+
+use anyhow::{bail, Context, Result as AnyResult};
+use serde::Serialize;
+use shakmaty::fen::Epd;
+use shakmaty::san::SanPlus;
+use shakmaty::uci::Uci;
+use shakmaty::{Chess, EnPassantMode, Position};
+use std::collections::HashMap;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Opening {
+    pub eco: String,
+    pub name: String,
+}
+
+/// Named openings by the EPD of their final position.
+#[derive(Debug, Default)]
+pub struct Openings {
+    by_epd: HashMap<String, Opening>,
+}
+
+impl Openings {
+    /// Read a table from `path`; see the module docs for the format.
+    pub fn load(path: &str) -> AnyResult<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("read {path}"))?;
+        Self::parse(&text).with_context(|| format!("parse {path}"))
+    }
+
+    /// Parse a table; a header row starting with `eco` is skipped.
+    pub fn parse(text: &str) -> AnyResult<Self> {
+        let mut by_epd = HashMap::new();
+        for (i, row) in text.lines().enumerate() {
+            let mut cols = row.split('\t');
+            let (Some(eco), Some(name), Some(pgn)) = (cols.next(), cols.next(), cols.next()) else {
+                if row.trim().is_empty() { continue; }
+                bail!("line {}: expected eco, name and pgn columns", i + 1);
+            };
+            if eco == "eco" { continue; }
+            let pos = play_sans(pgn).with_context(|| format!("line {}", i + 1))?;
+            let opening = Opening { eco: eco.to_owned(), name: name.to_owned() };
+            by_epd.insert(epd(&pos), opening);
+        }
+        Ok(Self { by_epd })
+    }
+
+    /// The opening of the last named position reached by playing the
+    /// comma‑separated UCI moves `play` from `start`; `None` if the line
+    /// never reaches one or does not parse.
+    #[must_use] pub fn of_line(&self, start: &Chess, play: &str) -> Option<Opening> {
+        let mut pos = start.clone();
+        let mut named = self.by_epd.get(&epd(&pos));
+        for uci in play.split(',').map(str::trim).filter(|m| !m.is_empty()) {
+            let mv = Uci::from_ascii(uci.as_bytes()).ok()?.to_move(&pos).ok()?;
+            pos.play_unchecked(&mv);
+            named = self.by_epd.get(&epd(&pos)).or(named);
+        }
+        named.cloned()
+    }
+}
+
+/// Play SAN movetext such as `1. e4 e5 2. Nf3` from the start position.
+fn play_sans(pgn: &str) -> AnyResult<Chess> {
+    let mut pos = Chess::default();
+    for token in pgn.split_whitespace() {
+        let san = token.trim_start_matches(|c: char| c.is_ascii_digit() || c == '.');
+        if san.is_empty() { continue; }
+        let mv = san
+            .parse::<SanPlus>()
+            .ok()
+            .and_then(|s| s.san.to_move(&pos).ok())
+            .with_context(|| format!("illegal move {san:?}"))?;
+        pos.play_unchecked(&mv);
+    }
+    Ok(pos)
+}
+
+fn epd(pos: &Chess) -> String {
+    Epd::from_position(pos.clone(), EnPassantMode::Legal).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TABLE: &str = "eco\tname\tpgn\n\
+        B00\tKing's Pawn Game\t1. e4\n\
+        B20\tSicilian Defense\t1. e4 c5\n\
+        B27\tSicilian Defense: Hyperaccelerated Dragon\t1. e4 c5 2. Nf3 g6\n";
+
+    #[test]
+    fn lines_are_named_after_their_deepest_named_position() {
+        let openings = Openings::parse(TABLE).unwrap();
+        let start = Chess::default();
+        let name = |play| openings.of_line(&start, play).map(|o| o.name);
+
+        assert_eq!(name(""), None);
+        assert_eq!(name("e2e4").as_deref(), Some("King's Pawn Game"));
+        assert_eq!(name("e2e4,c7c5,g1f3").as_deref(), Some("Sicilian Defense"));
+        let dragon = openings.of_line(&start, "e2e4,c7c5,g1f3,g7g6").unwrap();
+        assert_eq!(dragon.eco, "B27");
+        assert_eq!(name("d2d4"), None);
+    }
+
+    #[test]
+    fn bad_rows_name_their_line() {
+        let err = Openings::parse("A00\tNonsense\t1. e5\n").unwrap_err();
+        assert!(format!("{err:#}").contains("line 1"));
+        assert!(Openings::parse("A00 no tabs\n").is_err());
+    }
+}
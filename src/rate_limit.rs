@@ -0,0 +1,332 @@
+//! rate_limit.rs – API keys, token buckets and daily quotas.
+//!
+//! A request carrying a configured key draws from that key's bucket and
+//! quota; one without draws from a bucket per client IP (per /64 for IPv6,
+//! which hands each host a whole subnet), unless keys are required.
+//! Buckets refill continuously, so a rejected client learns from
+//! `Retry-After` exactly when its next request will pass.  A batch costs a
+//! token and a unit of quota per position, like that many requests.
+
+use crate::api_error::ApiError;
+use crate::config::{self, Rate};
+use crate::metrics::Exposition;
+use lru::LruCache;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// IP buckets kept; the least recently used one makes room for a new IP.
+const MAX_TRACKED_IPS: usize = 100_000;
+
+const DAY_SECS: i64 = 86_400;
+
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(rate: Rate, now: Instant) -> Self {
+        Self { tokens: f64::from(rate.burst), updated: now }
+    }
+
+    fn refill(&mut self, rate: Rate, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate.per_second).min(f64::from(rate.burst));
+        self.updated = now;
+    }
+
+    /// Take `n` tokens, or say how long until they are available.
+    fn take(&mut self, rate: Rate, now: Instant, n: u32) -> Result<(), Duration> {
+        self.refill(rate, now);
+        let n = f64::from(n);
+        if self.tokens >= n {
+            self.tokens -= n;
+            return Ok(());
+        }
+        if rate.per_second <= 0.0 { return Err(Duration::from_secs(DAY_SECS as u64)); }
+        Err(Duration::from_secs_f64((n - self.tokens) / rate.per_second))
+    }
+
+    /// Take `cost` tokens; a cost over the burst could never pass.
+    fn draw(&mut self, rate: Rate, now: Instant, cost: u32) -> Result<(), ApiError> {
+        if cost > rate.burst { return Err(too_costly(rate.burst.into())); }
+        self.take(rate, now, cost).map_err(limited)
+    }
+}
+
+struct Client {
+    name: String,
+    rate: Option<Rate>,
+    daily_quota: Option<u64>,
+    bucket: Mutex<Option<Bucket>>,
+    /// Requests counted towards the quota of the current UTC day.
+    today: Mutex<(i64, u64)>,
+    allowed: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl Client {
+    /// The quota is checked first and held while the bucket is drawn from,
+    /// so a request rejected by either costs nothing.
+    fn admit(&self, now: Instant, unix_secs: i64, cost: u32) -> Result<(), ApiError> {
+        let mut today = self.today.lock().expect("rate limiter poisoned");
+        let day = unix_secs.div_euclid(DAY_SECS);
+        if today.0 != day { *today = (day, 0); }
+        if let Some(quota) = self.daily_quota {
+            if u64::from(cost) > quota { return Err(too_costly(quota)); }
+            if today.1 + u64::from(cost) > quota {
+                let until_midnight = (day + 1) * DAY_SECS - unix_secs;
+                return Err(ApiError::RateLimited { retry_after: until_midnight.max(1) as u64 });
+            }
+        }
+        if let Some(rate) = self.rate {
+            let mut bucket = self.bucket.lock().expect("rate limiter poisoned");
+            let bucket = bucket.get_or_insert_with(|| Bucket::full(rate, now));
+            bucket.draw(rate, now, cost)?;
+        }
+        today.1 += u64::from(cost);
+        Ok(())
+    }
+}
+
+/// The bucket `ip` draws from: IPv4 addresses (also when mapped into
+/// IPv6) on their own, IPv6 ones by /64.
+fn ip_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !u128::from(u64::MAX))),
+        },
+    }
+}
+
+fn too_costly(limit: u64) -> ApiError {
+    ApiError::BadRequest(format!("at most {limit} positions per request with this client's limits"))
+}
+
+fn limited(wait: Duration) -> ApiError {
+    // Round up: retrying a fraction of a second early would fail again.
+    ApiError::RateLimited { retry_after: wait.as_secs() + u64::from(wait.subsec_nanos() > 0) }
+}
+
+/// Admission control for the API.
+pub struct Limiter {
+    /// By key.
+    clients: HashMap<String, Client>,
+    require_key: bool,
+    ip_rate: Option<Rate>,
+    /// By `ip_key`.
+    by_ip: Mutex<LruCache<IpAddr, Bucket>>,
+    ip_rejected: AtomicU64,
+    unauthorized: AtomicU64,
+}
+
+impl Limiter {
+    pub fn new(cfg: &config::Server) -> Result<Self, String> {
+        let mut clients = HashMap::with_capacity(cfg.api_keys.len());
+        for key in &cfg.api_keys {
+            let client = Client {
+                name: key.name.clone(),
+                rate: key.rate,
+                daily_quota: key.daily_quota,
+                bucket: Mutex::new(None),
+                today: Mutex::new((0, 0)),
+                allowed: AtomicU64::new(0),
+                rejected: AtomicU64::new(0),
+            };
+            if clients.insert(key.key.clone(), client).is_some() {
+                return Err(format!("API key of {:?} is listed twice", key.name));
+            }
+        }
+        Ok(Self {
+            clients,
+            require_key: cfg.require_api_key,
+            ip_rate: cfg.ip_rate,
+            by_ip: Mutex::new(LruCache::new(NonZeroUsize::new(MAX_TRACKED_IPS).expect("no IP buckets"))),
+            ip_rejected: AtomicU64::new(0),
+            unauthorized: AtomicU64::new(0),
+        })
+    }
+
+    /// Whether a request with `key` from `ip` may proceed.
+    pub fn admit(&self, key: Option<&str>, ip: Option<IpAddr>) -> Result<(), ApiError> {
+        self.admit_n(key, ip, 1)
+    }
+
+    /// `admit` for `cost` requests at once, such as the positions of a batch;
+    /// a cost of `0` only checks the key.
+    pub fn admit_n(&self, key: Option<&str>, ip: Option<IpAddr>, cost: u32) -> Result<(), ApiError> {
+        let now = Instant::now();
+        match key.map(|k| self.clients.get(k)) {
+            Some(Some(client)) => {
+                let unix_secs = chrono::Utc::now().timestamp();
+                let verdict = client.admit(now, unix_secs, cost);
+                let (counter, n) = match verdict {
+                    Ok(()) => (&client.allowed, cost),
+                    Err(_) => (&client.rejected, 1),
+                };
+                counter.fetch_add(u64::from(n), Ordering::Relaxed);
+                verdict
+            }
+            Some(None) => self.unauthorized(),
+            None if self.require_key => self.unauthorized(),
+            None => self.admit_ip(ip, now, cost),
+        }
+    }
+
+    fn unauthorized(&self) -> Result<(), ApiError> {
+        self.unauthorized.fetch_add(1, Ordering::Relaxed);
+        Err(ApiError::Unauthorized)
+    }
+
+    fn admit_ip(&self, ip: Option<IpAddr>, now: Instant, cost: u32) -> Result<(), ApiError> {
+        let (Some(rate), Some(ip)) = (self.ip_rate, ip) else { return Ok(()) };
+        let mut by_ip = self.by_ip.lock().expect("rate limiter poisoned");
+        let verdict = by_ip.get_or_insert_mut(ip_key(ip), || Bucket::full(rate, now))
+            .draw(rate, now, cost);
+        if verdict.is_err() { self.ip_rejected.fetch_add(1, Ordering::Relaxed); }
+        verdict
+    }
+
+    /// Usage of keyed clients, plus rejections of keyless ones.  Only with
+    /// `per_key` is it broken down by key name; otherwise the names stay out
+    /// of the exposition and keyed usage is summed.
+    pub fn render(&self, out: &mut Exposition, per_key: bool) {
+        let mut clients: Vec<&Client> = self.clients.values().collect();
+        clients.sort_by(|a, b| a.name.cmp(&b.name));
+        let families = [
+            ("chess_api_key_requests_total", "Requests admitted with an API key; a batch counts its positions.", false),
+            ("chess_api_key_rejected_total", "Requests over an API key's limits.", true),
+        ];
+        for (name, help, rejected) in families {
+            out.family(name, "counter", help);
+            let count = |c: &Client| (if rejected { &c.rejected } else { &c.allowed }).load(Ordering::Relaxed);
+            if per_key {
+                for c in &clients { out.sample(name, &[("key", &c.name)], count(c) as f64); }
+            } else {
+                out.sample(name, &[], clients.iter().map(|c| count(c)).sum::<u64>() as f64);
+            }
+        }
+        out.family("chess_api_ip_rejected_total", "counter", "Keyless requests over the per-IP limit.")
+            .sample("chess_api_ip_rejected_total", &[], self.ip_rejected.load(Ordering::Relaxed) as f64)
+            .family("chess_api_unauthorized_total", "counter", "Requests with a missing or unknown key.")
+            .sample("chess_api_unauthorized_total", &[], self.unauthorized.load(Ordering::Relaxed) as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_refills_at_its_rate() {
+        let rate = Rate { per_second: 2.0, burst: 2 };
+        let start = Instant::now();
+        let mut bucket = Bucket::full(rate, start);
+        assert!(bucket.take(rate, start, 1).is_ok());
+        assert!(bucket.take(rate, start, 1).is_ok());
+        let wait = bucket.take(rate, start, 1).unwrap_err();
+        assert!((wait.as_secs_f64() - 0.5).abs() < 1e-9);
+        assert!(bucket.take(rate, start + Duration::from_millis(500), 1).is_ok());
+        // Never more than `burst` saved up.
+        let later = start + Duration::from_secs(60);
+        assert!(bucket.take(rate, later, 1).is_ok() && bucket.take(rate, later, 1).is_ok());
+        assert!(bucket.take(rate, later, 1).is_err());
+    }
+
+    #[test]
+    fn batches_cost_one_token_per_position() {
+        let cfg: config::Server = serde_json::from_value(serde_json::json!({
+            "db_path": "db",
+            "ip_rate": {"per_second": 0.0, "burst": 10},
+            "api_keys": [{"name": "a", "key": "k", "daily_quota": 5}],
+        }))
+        .unwrap();
+        let limiter = Limiter::new(&cfg).unwrap();
+        let ip = Some(IpAddr::from([192, 0, 2, 1]));
+        assert!(matches!(limiter.admit_n(None, ip, 11), Err(ApiError::BadRequest(_))));
+        assert!(limiter.admit_n(None, ip, 8).is_ok());
+        assert!(matches!(limiter.admit_n(None, ip, 3), Err(ApiError::RateLimited { .. })));
+        assert!(limiter.admit_n(None, ip, 2).is_ok());
+
+        assert!(limiter.admit_n(Some("k"), None, 4).is_ok());
+        assert!(matches!(limiter.admit_n(Some("k"), None, 2), Err(ApiError::RateLimited { .. })));
+        assert!(limiter.admit(Some("k"), None).is_ok());
+        assert_eq!(limiter.clients["k"].allowed.load(Ordering::Relaxed), 5);
+    }
+
+    #[test]
+    fn quota_rejections_keep_their_tokens() {
+        let cfg: config::Server = serde_json::from_value(serde_json::json!({
+            "db_path": "db",
+            "api_keys": [{"name": "a", "key": "k", "rate": {"per_second": 0.0, "burst": 3}, "daily_quota": 2}],
+        }))
+        .unwrap();
+        let limiter = Limiter::new(&cfg).unwrap();
+        let tokens = || limiter.clients["k"].bucket.lock().unwrap().map(|b| b.tokens);
+        assert!(limiter.admit_n(Some("k"), None, 2).is_ok());
+        assert_eq!(tokens(), Some(1.0));
+        assert!(matches!(limiter.admit(Some("k"), None), Err(ApiError::RateLimited { .. })));
+        assert_eq!(tokens(), Some(1.0));
+        assert_eq!(limiter.clients["k"].today.lock().unwrap().1, 2);
+    }
+
+    #[test]
+    fn key_names_are_only_rendered_per_key() {
+        let cfg: config::Server = serde_json::from_value(serde_json::json!({
+            "db_path": "db",
+            "api_keys": [{"name": "acme", "key": "k1"}, {"name": "globex", "key": "k2"}],
+        }))
+        .unwrap();
+        let limiter = Limiter::new(&cfg).unwrap();
+        assert!(limiter.admit_n(Some("k1"), None, 3).is_ok());
+        assert!(limiter.admit(Some("k2"), None).is_ok());
+        let rendered = |per_key| {
+            let mut out = Exposition::default();
+            limiter.render(&mut out, per_key);
+            out.finish()
+        };
+
+        let public = rendered(false);
+        assert!(!public.contains("acme") && !public.contains("globex"));
+        assert!(public.contains("chess_api_key_requests_total 4\n"), "{public}");
+        let private = rendered(true);
+        assert!(private.contains(r#"chess_api_key_requests_total{key="acme"} 3"#), "{private}");
+        assert!(private.contains(r#"chess_api_key_requests_total{key="globex"} 1"#), "{private}");
+    }
+
+    #[test]
+    fn ipv6_clients_share_their_subnet() {
+        let key = |s: &str| ip_key(s.parse().unwrap());
+        assert_eq!(key("2001:db8:1:2:aaaa::1"), key("2001:db8:1:2:bbbb::2"));
+        assert_eq!(key("2001:db8:1:2:aaaa::1"), "2001:db8:1:2::".parse::<IpAddr>().unwrap());
+        assert_ne!(key("2001:db8:1:2::1"), key("2001:db8:1:3::1"));
+        assert_eq!(key("::ffff:192.0.2.1"), key("192.0.2.1"));
+        assert_ne!(key("192.0.2.1"), key("192.0.2.2"));
+    }
+
+    #[test]
+    fn ip_buckets_are_bounded() {
+        let cfg: config::Server = serde_json::from_value(serde_json::json!({
+            "db_path": "db",
+            "ip_rate": {"per_second": 0.0, "burst": 1},
+        }))
+        .unwrap();
+        let limiter = Limiter::new(&cfg).unwrap();
+        let ip = |n: u32| Some(IpAddr::from(n.to_be_bytes()));
+        assert!(limiter.admit(None, ip(0)).is_ok());
+        assert!(limiter.admit(None, ip(0)).is_err());
+        for n in 1..=MAX_TRACKED_IPS as u32 {
+            assert!(limiter.admit(None, ip(n)).is_ok());
+        }
+        assert_eq!(limiter.by_ip.lock().unwrap().len(), MAX_TRACKED_IPS);
+        // The oldest client was forgotten to make room.
+        assert!(limiter.admit(None, ip(0)).is_ok());
+    }
+}
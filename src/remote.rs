@@ -0,0 +1,417 @@
+//! remote.rs – stream archives straight from HTTP(S) URLs.
+//!
+//! `RangeReader` turns a download into a plain `Read` that survives dropped
+//! connections: it remembers how many bytes it has handed out and reconnects
+//! with `Range: bytes=<pos>-` (guarded by `If-Range`, so a file replaced on
+//! the server is not silently spliced).  `Verified` hashes the compressed
+//! bytes on the way through and fails the stream at EOF if they do not match
+//! the published SHA‑256.
+
+use anyhow::{anyhow, bail, ensure, Context, Result as AnyResult};
+use crate::file;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::ops::Range;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::Duration;
+
+/// Consecutive reconnects allowed without making progress.
+const MAX_RETRIES: u32 = 8;
+
+#[must_use] pub fn is_url(s: &str) -> bool {
+    s.starts_with("http://") || s.starts_with("https://")
+}
+
+#[must_use] pub fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout_connect(Duration::from_secs(30))
+        .timeout_read(Duration::from_secs(60))
+        .build()
+}
+
+/// Last path segment of `url`, without any query string.
+#[must_use] pub fn file_name(url: &str) -> &str {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// What a `HEAD` request tells us about a remote archive.
+#[derive(Clone, Debug)]
+pub struct Remote {
+    pub url: String,
+    pub len: u64,
+    /// `ETag`, or failing that `Last-Modified`; identifies this version.
+    pub validator: Option<String>,
+}
+
+impl Remote {
+    pub fn head(agent: &ureq::Agent, url: &str) -> AnyResult<Self> {
+        let resp = agent.head(url).call().with_context(|| format!("HEAD {url}"))?;
+        let len = resp
+            .header("Content-Length")
+            .and_then(|v| v.parse().ok())
+            .ok_or_else(|| anyhow!("{url}: no Content-Length"))?;
+        if resp.header("Accept-Ranges") != Some("bytes") {
+            eprintln!("[remote] {url}: server does not advertise range requests");
+        }
+        let validator = resp
+            .header("ETag")
+            .or_else(|| resp.header("Last-Modified"))
+            .map(str::to_owned);
+        Ok(Self { url: url.to_owned(), len, validator })
+    }
+
+    /// Content identity, from two small range requests.  A server without
+    /// range support would have to send the whole archive for the tail, so
+    /// the id comes from `validator` instead, see `file::by_validator`; only
+    /// without one is the body streamed up to the tail.
+    pub fn id(&self, agent: &ureq::Agent) -> AnyResult<file::Id> {
+        let [head, tail] = file::sample_ranges(self.len);
+        let (head, ranged) = self.fetch(agent, head)?;
+        if !ranged && !tail.is_empty() {
+            if let Some(validator) = &self.validator {
+                return Ok(file::by_validator(self.len, validator));
+            }
+            eprintln!("[remote] {}: no range support, reading up to byte {}", self.url, tail.end);
+        }
+        let (tail, _) = self.fetch(agent, tail)?;
+        Ok(file::digest(self.len, &head, &tail))
+    }
+
+    /// The bytes of `range`, and whether the server sent just those.
+    fn fetch(&self, agent: &ureq::Agent, range: Range<u64>) -> AnyResult<(Vec<u8>, bool)> {
+        if range.is_empty() { return Ok((Vec::new(), true)); }
+        let len = range.end - range.start;
+        let resp = agent
+            .get(&self.url)
+            .set("Range", &format!("bytes={}-{}", range.start, range.end - 1))
+            .call()
+            .with_context(|| format!("GET {} {range:?}", self.url))?;
+        let status = resp.status();
+        let mut body = resp.into_reader();
+        match status {
+            206 => {}
+            // The whole body: skip to the range.
+            200 => { io::copy(&mut (&mut body).take(range.start), &mut io::sink())?; }
+            s => bail!("{}: expected bytes {range:?}, got HTTP {s}", self.url),
+        }
+        let mut buf = Vec::with_capacity(len as usize);
+        body.take(len).read_to_end(&mut buf)?;
+        ensure!(buf.len() as u64 == len, "{}: short range response", self.url);
+        Ok((buf, status == 206))
+    }
+}
+
+/// Fetch a checksum file in `sha256sum` format (`<hex>  <name>` per line).
+pub fn checksums(agent: &ureq::Agent, url: &str) -> AnyResult<HashMap<String, String>> {
+    let text = agent
+        .get(url)
+        .call()
+        .with_context(|| format!("GET {url}"))?
+        .into_string()?;
+    Ok(parse_checksums(&text))
+}
+
+fn parse_checksums(text: &str) -> HashMap<String, String> {
+    text.lines()
+        .filter_map(|line| {
+            let (hex, name) = line.trim().split_once(char::is_whitespace)?;
+            let name = name.trim_start().trim_start_matches('*');   // binary marker
+            Some((file_name(name).to_owned(), hex.to_ascii_lowercase()))
+        })
+        .collect()
+}
+
+/// Resumable body of a remote archive.
+pub struct RangeReader {
+    agent: ureq::Agent,
+    remote: Remote,
+    pos: u64,
+    body: Option<Box<dyn Read + Send + Sync>>,
+    retries: u32,
+}
+
+impl RangeReader {
+    #[must_use] pub fn new(agent: ureq::Agent, remote: Remote) -> Self {
+        Self { agent, remote, pos: 0, body: None, retries: 0 }
+    }
+
+    fn connect(&mut self) -> Result<(), Failure> {
+        let mut req = self.agent.get(&self.remote.url);
+        if self.pos > 0 {
+            req = req.set("Range", &format!("bytes={}-", self.pos));
+            if let Some(v) = &self.remote.validator { req = req.set("If-Range", v); }
+        }
+        let resp = match req.call() {
+            Ok(resp) => resp,
+            // Overloaded or failing servers may recover; a 404, 403 or 416
+            // will not.
+            Err(ureq::Error::Status(s, _)) => {
+                let err = io::Error::other(format!("{}: HTTP {s}", self.remote.url));
+                return Err(if s == 429 || s >= 500 { Failure::Transient(err) } else { Failure::Permanent(err) });
+            }
+            Err(err @ ureq::Error::Transport(_)) => return Err(Failure::Transient(io::Error::other(err))),
+        };
+        match resp.status() {
+            206 => {}
+            200 if self.pos == 0 => {}
+            // The whole (possibly replaced) file again: resuming cannot work.
+            s => {
+                return Err(Failure::Permanent(io::Error::other(format!(
+                    "{}: expected a range from byte {}, got HTTP {s}",
+                    self.remote.url, self.pos,
+                ))));
+            }
+        }
+        self.body = Some(resp.into_reader());
+        Ok(())
+    }
+
+    /// Drop the connection and back off before the next attempt.
+    fn retry(&mut self, err: io::Error) -> io::Result<()> {
+        self.body = None;
+        self.retries += 1;
+        if self.retries > MAX_RETRIES { return Err(err); }
+        eprintln!(
+            "[remote] {} at byte {}: {err}; retry {}/{MAX_RETRIES}",
+            self.remote.url, self.pos, self.retries,
+        );
+        std::thread::sleep(Duration::from_secs(1 << self.retries.min(5)));
+        Ok(())
+    }
+}
+
+/// Why `RangeReader::connect` failed: worth retrying or not.
+enum Failure {
+    Transient(io::Error),
+    Permanent(io::Error),
+}
+
+impl Read for RangeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.pos >= self.remote.len || buf.is_empty() { return Ok(0); }
+            if self.body.is_none() {
+                match self.connect() {
+                    Ok(()) => {}
+                    Err(Failure::Transient(err)) => { self.retry(err)?; continue; }
+                    Err(Failure::Permanent(err)) => return Err(err),
+                }
+            }
+            let body = self.body.as_mut().expect("connected above");
+            match body.read(buf) {
+                Ok(0) => {
+                    let err = io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed early");
+                    self.retry(err)?;
+                }
+                Ok(n) => {
+                    self.pos += n as u64;
+                    self.retries = 0;
+                    return Ok(n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => self.retry(err)?,
+            }
+        }
+    }
+}
+
+/// Outcome of a `Verified` stream, shared with whoever reads it.
+#[derive(Debug, Default)]
+pub struct Verification(AtomicU8);
+
+impl Verification {
+    const PENDING: u8 = 0;
+    const MATCHED: u8 = 1;
+    const MISMATCHED: u8 = 2;
+
+    /// Error unless the whole stream was read and its digest matched.
+    pub fn ensure(&self, what: &str) -> AnyResult<()> {
+        match self.0.load(Ordering::Acquire) {
+            Self::MATCHED => Ok(()),
+            Self::MISMATCHED => bail!("{what}: SHA‑256 mismatch"),
+            _ => bail!("{what}: stream ended before the checksum could be verified"),
+        }
+    }
+}
+
+/// Hashes everything read through it and checks the digest at EOF.
+pub struct Verified<R> {
+    inner: R,
+    hasher: Sha256,
+    expected: String,
+    result: Arc<Verification>,
+}
+
+impl<R: Read> Verified<R> {
+    pub fn new(inner: R, expected: String) -> (Self, Arc<Verification>) {
+        let result = Arc::new(Verification::default());
+        let reader = Self { inner, hasher: Sha256::new(), expected, result: result.clone() };
+        (reader, result)
+    }
+}
+
+impl<R: Read> Read for Verified<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            self.hasher.update(&buf[..n]);
+        } else if self.result.0.load(Ordering::Acquire) == Verification::PENDING {
+            let digest = self.hasher.finalize_reset();
+            let hex: String = digest.iter().map(|b| format!("{b:02x}")).collect();
+            let ok = hex == self.expected;
+            let state = if ok { Verification::MATCHED } else { Verification::MISMATCHED };
+            self.result.0.store(state, Ordering::Release);
+            if !ok {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "SHA‑256 mismatch"));
+            }
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    /// Minimal static file server with `Range` support.  The first `GET`
+    /// is cut off halfway through the body to force a resume.
+    fn serve(body: Vec<u8>) -> String { serve_with(body, true, true) }
+
+    /// Static file server answering `Range: bytes=a-[b]` with 206 if
+    /// `ranges`, else always with the whole body.  With `cut`, the first
+    /// `GET` ends halfway through.
+    fn serve_with(body: Vec<u8>, ranges: bool, cut: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut gets = 0;
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let (mut request, mut range) = (String::new(), None);
+                reader.read_line(&mut request).unwrap();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() { break; }
+                    if let Some(r) = line.strip_prefix("Range: bytes=") {
+                        let (from, to) = r.trim().split_once('-').unwrap();
+                        let end = if to.is_empty() { body.len() } else { to.parse::<usize>().unwrap() + 1 };
+                        range = Some(from.parse::<usize>().unwrap()..end);
+                    }
+                }
+                let range = range.filter(|_| ranges);
+                let status = if range.is_some() { "206 Partial Content" } else { "200 OK" };
+                let part = &body[range.unwrap_or(0..body.len())];
+                write!(
+                    stream,
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\n{}\
+                     ETag: \"v1\"\r\nConnection: close\r\n\r\n",
+                    part.len(),
+                    if ranges { "Accept-Ranges: bytes\r\n" } else { "" },
+                )
+                .unwrap();
+                if request.starts_with("GET") {
+                    gets += 1;
+                    let sent = if cut && gets == 1 { part.len() / 2 } else { part.len() };
+                    let _ = stream.write_all(&part[..sent]);   // clients may hang up early
+                }
+            }
+        });
+        format!("http://{addr}/2013-01.pgn.zst")
+    }
+
+    #[test]
+    fn resumes_and_verifies_download() {
+        let body: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let sum: String = Sha256::digest(&body).iter().map(|b| format!("{b:02x}")).collect();
+        let url = serve(body.clone());
+
+        let agent = agent();
+        let remote = Remote::head(&agent, &url).unwrap();
+        assert_eq!(remote.len, body.len() as u64);
+        assert_eq!(file_name(&url), "2013-01.pgn.zst");
+
+        let sums = parse_checksums(&format!("{sum}  {}\n", file_name(&url)));
+        let (mut reader, check) =
+            Verified::new(RangeReader::new(agent, remote), sums["2013-01.pgn.zst"].clone());
+        let mut got = Vec::new();
+        reader.read_to_end(&mut got).unwrap();
+
+        assert_eq!(got, body);
+        check.ensure(&url).unwrap();
+    }
+
+    #[test]
+    fn id_samples_ranges_or_falls_back_to_the_etag() {
+        let body: Vec<u8> = (0..2 * file::SAMPLE_BYTES + 1000).map(|i| (i % 251) as u8).collect();
+        let dir = tempfile::tempdir().unwrap();
+        let local = dir.path().join("2013-01.pgn.zst");
+        fs::write(&local, &body).unwrap();
+        let agent = agent();
+
+        let remote = Remote::head(&agent, &serve_with(body.clone(), true, false)).unwrap();
+        assert_eq!(remote.id(&agent).unwrap(), file::id(&local.to_string_lossy()).unwrap());
+
+        // Without ranges the tail would cost the whole download.
+        let mut remote = Remote::head(&agent, &serve_with(body.clone(), false, false)).unwrap();
+        assert_eq!(remote.id(&agent).unwrap(), file::by_validator(body.len() as u64, "\"v1\""));
+        remote.validator = None;
+        assert_eq!(remote.id(&agent).unwrap(), file::id(&local.to_string_lossy()).unwrap());
+    }
+
+    #[test]
+    fn verified_rejects_a_mismatched_stream() {
+        let body = b"1. e4 e5 1-0\n".to_vec();
+        let sum: String = Sha256::digest(&body).iter().map(|b| format!("{b:02x}")).collect();
+
+        let (mut reader, check) = Verified::new(&body[..], sum.clone());
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        check.ensure("whole").unwrap();
+
+        let (mut reader, check) = Verified::new(&body[..4], sum.clone());
+        assert_eq!(io::copy(&mut reader, &mut io::sink()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(check.ensure("short").unwrap_err().to_string().contains("mismatch"));
+
+        // Stopping early is not a match either.
+        let (mut reader, check) = Verified::new(&body[..], sum);
+        reader.read_exact(&mut [0; 4]).unwrap();
+        assert!(check.ensure("partial").is_err());
+    }
+
+    #[test]
+    fn client_errors_are_not_retried() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicU8::new(0));
+        std::thread::spawn({
+            let requests = requests.clone();
+            move || {
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        if line.trim().is_empty() { break; }
+                    }
+                    requests.fetch_add(1, Ordering::Relaxed);
+                    let _ = stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+                }
+            }
+        });
+
+        let remote = Remote { url: format!("http://{addr}/gone.pgn.zst"), len: 100, validator: None };
+        let started = std::time::Instant::now();
+        let err = RangeReader::new(agent(), remote).read(&mut [0; 16]).unwrap_err();
+        assert!(err.to_string().contains("404"), "{err}");
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,146 @@
+//! replay.rs – trie of recently replayed opening prefixes.
+//!
+//! Most games share their first ten or so moves, so converting those SANs
+//! to moves and hashing the resulting positions is repeated millions of
+//! times.  Each worker keeps a `radix_trie` keyed by the SAN text of a move
+//! prefix (`"e4 e5 Nf3 "`); the value holds the keys produced by the prefix's
+//! last move and the board after it, linked to the step before.  Replay
+//! jumps to the deepest cached prefix and only computes the rest.
+
+use crate::chess_db::Key;
+use radix_trie::{Trie, TrieCommon};
+use shakmaty::{san::SanPlus, Chess};
+use std::io::Write;
+use std::mem::size_of;
+use std::sync::Arc;
+
+/// Rough heap cost of one cached step: the `Arc`ed `Step`, its SAN key and
+/// the trie node holding both.
+pub const STEP_BYTES: usize = size_of::<Step>() + 256;
+
+/// Result of playing move number `ply` of a cached prefix.
+pub struct Step {
+    /// `ps` key of the position before the move.
+    pub pos_key: Key,
+    /// `pms` key of the move itself.
+    pub move_key: Key,
+    /// Position after the move.
+    pub board: Chess,
+    pub ply: usize,
+    parent: Option<Arc<Step>>,
+}
+
+impl Step {
+    #[must_use] pub fn new(
+        pos_key: Key,
+        move_key: Key,
+        board: Chess,
+        ply: usize,
+        parent: Option<Arc<Self>>,
+    ) -> Self {
+        Self { pos_key, move_key, board, ply, parent }
+    }
+
+    /// This step and all of its ancestors, first move first.
+    #[must_use] pub fn path(&self) -> Vec<&Self> {
+        let mut path = vec![self];
+        let mut cur = self.parent.as_deref();
+        while let Some(step) = cur {
+            path.push(step);
+            cur = step.parent.as_deref();
+        }
+        path.reverse();
+        path
+    }
+}
+
+/// Per‑worker cache of the first `max_plies` moves of recent games.
+pub struct PrefixCache {
+    trie: Trie<Vec<u8>, Arc<Step>>,
+    max_plies: usize,
+    capacity: usize,
+    /// SAN text of the current game's cacheable prefix …
+    key: Vec<u8>,
+    /// … and where each ply's prefix ends inside it.
+    ends: Vec<usize>,
+}
+
+impl PrefixCache {
+    /// `capacity` bounds the number of cached steps; the trie is reset when
+    /// it fills up so it tracks recently seen openings.
+    #[must_use] pub fn new(max_plies: usize, capacity: usize) -> Self {
+        Self { trie: Trie::new(), max_plies, capacity, key: Vec::new(), ends: Vec::new() }
+    }
+
+    /// Start a new game and return the step at the end of its deepest
+    /// cached prefix.
+    pub fn lookup(&mut self, sans: &[SanPlus]) -> Option<Arc<Step>> {
+        self.key.clear();
+        self.ends.clear();
+        for san_plus in sans.iter().take(self.max_plies) {
+            write!(self.key, "{} ", san_plus.san).expect("write to Vec");
+            self.ends.push(self.key.len());
+        }
+        self.trie.get_ancestor_value(&self.key).cloned()
+    }
+
+    /// Whether move `ply` of the current game should be cached.
+    #[inline] #[must_use] pub fn wants(&self, ply: usize) -> bool { ply < self.ends.len() }
+
+    /// Cache `step` under the current game's prefix ending at `step.ply`.
+    pub fn insert(&mut self, step: Arc<Step>) {
+        if self.trie.len() >= self.capacity { self.trie = Trie::new(); }
+        let end = self.ends[step.ply];
+        self.trie.insert(self.key[..end].to_vec(), step);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shakmaty::Position;
+
+    fn sans(moves: &str) -> Vec<SanPlus> {
+        moves.split_whitespace().map(|m| m.parse().unwrap()).collect()
+    }
+
+    /// Replay `game` from its cached prefix, caching what `wants` asks for.
+    fn replay(cache: &mut PrefixCache, game: &[SanPlus]) -> (Option<usize>, usize) {
+        let mut parent = cache.lookup(game);
+        let hit = parent.as_ref().map(|s| s.ply);
+        let (mut board, start) = parent.as_ref().map_or((Chess::default(), 0), |s| (s.board.clone(), s.ply + 1));
+        let mut inserted = 0;
+        for (ply, san_plus) in game.iter().enumerate().skip(start) {
+            let m = san_plus.san.to_move(&board).unwrap();
+            board.play_unchecked(&m);
+            if cache.wants(ply) {
+                let key = crate::chess_db::pos_key(san_plus.to_string().as_bytes());
+                let step = Arc::new(Step::new(key, key, board.clone(), ply, parent.take()));
+                cache.insert(step.clone());
+                parent = Some(step);
+                inserted += 1;
+            }
+        }
+        (hit, inserted)
+    }
+
+    #[test]
+    fn prefixes_hit_and_reset_when_full() {
+        let mut cache = PrefixCache::new(4, 6);
+        assert_eq!(replay(&mut cache, &sans("e4 e5 Nf3 Nc6 Bb5")), (None, 4));
+
+        // Shares two plies: resumes after e5 and caches the rest.
+        assert_eq!(replay(&mut cache, &sans("e4 e5 Nc3 Nf6")), (Some(1), 2));
+        let step = cache.lookup(&sans("e4 e5 Nc3 Nf6 Bc4")).unwrap();
+        let path: Vec<_> = step.path().iter().map(|s| s.ply).collect();
+        assert_eq!(path, [0, 1, 2, 3]);
+        let mut board = Chess::default();
+        for s in sans("e4 e5 Nc3 Nf6") { board.play_unchecked(&s.san.to_move(&board).unwrap()); }
+        assert_eq!(step.board.board(), board.board());
+
+        // The seventh step does not fit: the trie starts over.
+        assert_eq!(replay(&mut cache, &sans("d4 d5")), (None, 2));
+        assert!(cache.lookup(&sans("e4 e5")).is_none());
+        assert_eq!(cache.lookup(&sans("d4 d5 c4")).map(|s| s.ply), Some(1));
+    }
+}
@@ -1,6 +1,35 @@
-use rocksdb::{Options, BlockBasedOptions, SliceTransform};
+use rocksdb::{Options, BlockBasedOptions, Cache, SliceTransform, WriteBufferManager};
 
-#[must_use] pub fn tuned() -> Options {
+#[must_use] pub fn tuned() -> Options { with_block_cache(None) }
+
+/// `tuned()` with the block cache and every memtable sharing one limit of
+/// `block_cache + write_buffers` bytes.
+#[must_use] pub fn budgeted(block_cache: usize, write_buffers: usize) -> Options {
+    let cache = Cache::new_lru_cache(block_cache + write_buffers);
+    let mut opts = with_block_cache(Some(&cache));
+
+    let wbm = WriteBufferManager::new_write_buffer_manager_with_cache(
+        write_buffers,
+        true,                                   // stall writers, don't OOM
+        cache,
+    );
+    opts.set_write_buffer_manager(&wbm);
+    opts.set_write_buffer_size((write_buffers / 4).max(4 * 1024 * 1024));
+    opts.set_max_write_buffer_number(4);
+    opts
+}
+
+/// Adjust `opts` for a bulk load: no background compaction while SST files
+/// are ingested, bigger output files and every core for the final manual
+/// compaction.  `bulk::load` turns compaction back on when it is done.
+pub fn for_bulk_load(opts: &mut Options) {
+    opts.set_disable_auto_compactions(true);
+    opts.set_level_compaction_dynamic_level_bytes(true);
+    opts.set_target_file_size_base(256 * 1024 * 1024);
+    opts.increase_parallelism(i32::try_from(num_cpus::get()).unwrap_or(i32::MAX));
+}
+
+fn with_block_cache(cache: Option<&Cache>) -> Options {
     let mut opts = Options::default();
     opts.set_max_open_files(-1);
     opts.optimize_for_point_lookup(8 * 1024 * 1024);
@@ -10,6 +39,7 @@ use rocksdb::{Options, BlockBasedOptions, SliceTransform};
     bb.set_cache_index_and_filter_blocks(true);
     bb.set_pin_l0_filter_and_index_blocks_in_cache(true);
     bb.set_bloom_filter(10.0, false);           // <-- changed line
+    if let Some(cache) = cache { bb.set_block_cache(cache); }
     opts.set_block_based_table_factory(&bb);
 
     opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(11));
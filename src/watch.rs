@@ -0,0 +1,218 @@
+//! watch.rs – keep the process alive and ingest archives as they appear.
+//!
+//! `pgn_dir` is monitored with the platform's native watcher (inotify on
+//! Linux), falling back to polling where that is unavailable.  A file is
+//! only ingested once its size and mtime have stopped changing for
+//! `SETTLE`, so half‑copied dumps are not picked up.  Each new archive goes
+//! through `Session::run`, with the usual `FS` skip logic, progress bars and
+//! report.
+
+use crate::config;
+use crate::ingest::{self, Session};
+use anyhow::{bail, Result as AnyResult};
+use crossbeam_channel::{self as chan, RecvTimeoutError, Sender};
+use notify::{Event, PollWatcher, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long a file must stay unchanged before it is ingested.
+const SETTLE: Duration = Duration::from_secs(10);
+/// Scan interval of the polling fallback.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// How often pending files are re‑checked when no events arrive.
+const TICK: Duration = Duration::from_secs(1);
+
+/// Ingest everything already in `pgn_dir`, then wait for new archives.
+pub fn watch(cfg: &config::Ingest) -> AnyResult<()> {
+    anyhow::ensure!(!cfg.pgn_dir.is_empty(), "--watch needs a pgn_dir to watch");
+    let session = Session::open(cfg)?;
+
+    // Watch first so archives arriving during the catch-up queue up as
+    // events; ones it already ingested are skipped by their `FS` record.
+    let (tx, rx) = chan::unbounded();
+    let _watcher = start_watcher(Path::new(&cfg.pgn_dir), tx)?;
+
+    // Catch up on anything that arrived while we were not running.
+    eprintln!("{}", session.run(ingest::sources(cfg)?)?);
+    eprintln!("[watch] waiting for new archives in {}", cfg.pgn_dir);
+
+    let mut pending = Pending::new(SETTLE);
+    loop {
+        match rx.recv_timeout(TICK) {
+            Ok(Ok(event)) => event.paths.into_iter().for_each(|path| pending.add(path)),
+            Ok(Err(err)) => eprintln!("[watch] {err}"),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => bail!("file watcher stopped"),
+        }
+
+        for path in pending.ready() {
+            let archive = path.to_string_lossy().into_owned();
+            match session.run(vec![archive]) {
+                Ok(report) => eprintln!("{report}"),
+                Err(err) => eprintln!("[watch] {}: {err:#}", path.display()),
+            }
+        }
+    }
+}
+
+fn start_watcher(
+    dir: &Path,
+    tx: Sender<notify::Result<Event>>,
+) -> AnyResult<Box<dyn Watcher>> {
+    let native_tx = tx.clone();
+    let native = notify::recommended_watcher(move |res| { let _ = native_tx.send(res); })
+        .and_then(|mut w| w.watch(dir, RecursiveMode::NonRecursive).map(|()| w));
+    match native {
+        Ok(w) => Ok(Box::new(w)),
+        Err(err) => {
+            eprintln!(
+                "[watch] native watcher unavailable ({err}), polling every {}s",
+                POLL_INTERVAL.as_secs(),
+            );
+            let mut w = PollWatcher::new(
+                move |res| { let _ = tx.send(res); },
+                notify::Config::default().with_poll_interval(POLL_INTERVAL),
+            )?;
+            w.watch(dir, RecursiveMode::NonRecursive)?;
+            Ok(Box::new(w))
+        }
+    }
+}
+
+/// Archives seen by the watcher that have not settled yet.
+struct Pending {
+    files: HashMap<PathBuf, Growth>,
+    settle: Duration,
+}
+
+impl Pending {
+    /// Files become ready once unchanged for `settle`.
+    fn new(settle: Duration) -> Self { Self { files: HashMap::new(), settle } }
+
+    /// Track `path` if it is an archive.
+    fn add(&mut self, path: PathBuf) {
+        if ingest::is_archive(&path) {
+            self.files.entry(path).or_insert_with(Growth::new);
+        }
+    }
+
+    /// Settled files, in path order; they and vanished ones are dropped.
+    fn ready(&mut self) -> Vec<PathBuf> {
+        let mut ready = Vec::new();
+        let settle = self.settle;
+        self.files.retain(|path, growth| match growth.check(path, settle) {
+            State::Growing => true,
+            State::Settled => { ready.push(path.clone()); false }
+            State::Gone => false,
+        });
+        ready.sort();
+        ready
+    }
+}
+
+enum State { Growing, Settled, Gone }
+
+/// Last observed size and mtime of a pending file, and since when.
+/// Where the platform has no mtime, the size alone decides.
+struct Growth {
+    seen: Option<(u64, Option<SystemTime>)>,
+    since: Instant,
+}
+
+impl Growth {
+    fn new() -> Self { Self { seen: None, since: Instant::now() } }
+
+    fn check(&mut self, path: &Path, settle: Duration) -> State {
+        let Ok(meta) = fs::metadata(path) else { return State::Gone };
+        let now = Some((meta.len(), meta.modified().ok()));
+        if self.seen != now {
+            self.seen = now;
+            self.since = Instant::now();
+            State::Growing
+        } else if self.since.elapsed() >= settle {
+            State::Settled
+        } else {
+            State::Growing
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::thread::sleep;
+
+    const SETTLE: Duration = Duration::from_millis(200);
+
+    #[test]
+    fn files_are_ready_once_settled() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.pgn.zst");
+        fs::write(&path, b"abc").unwrap();
+        let mut pending = Pending::new(SETTLE);
+        pending.add(path.clone());
+        pending.add(dir.path().join("notes.txt"));
+        assert!(pending.ready().is_empty());
+        assert_eq!(pending.files.len(), 1);
+
+        sleep(SETTLE + SETTLE / 2);
+        assert_eq!(pending.ready(), [path]);
+        assert!(pending.files.is_empty());
+    }
+
+    #[test]
+    fn growing_files_wait_for_the_last_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.pgn.zst");
+        fs::write(&path, b"abc").unwrap();
+        let mut pending = Pending::new(SETTLE);
+        pending.add(path.clone());
+        assert!(pending.ready().is_empty());
+
+        sleep(SETTLE * 3 / 5);
+        fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(b"def").unwrap();
+        assert!(pending.ready().is_empty());
+        // Settled since it was first seen, but not since it grew.
+        sleep(SETTLE * 3 / 5);
+        assert!(pending.ready().is_empty());
+        sleep(SETTLE);
+        assert_eq!(pending.ready(), [path]);
+    }
+
+    #[test]
+    fn vanished_files_are_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.pgn.zst");
+        fs::write(&path, b"abc").unwrap();
+        let mut pending = Pending::new(SETTLE);
+        pending.add(path.clone());
+        assert!(pending.ready().is_empty());
+
+        fs::remove_file(&path).unwrap();
+        sleep(SETTLE + SETTLE / 2);
+        assert!(pending.ready().is_empty());
+        assert!(pending.files.is_empty());
+    }
+
+    #[test]
+    fn settled_archives_already_ingested_are_skipped() {
+        let t = config::TestArchives::new(serde_json::json!({}));
+        let game = "[Event \"Rated Blitz game\"]\n[Site \"https://lichess.org/aaaaaaaa\"]\n[Result \"1-0\"]\n\n1. e4 e5 1-0\n\n";
+        let archive = t.add("a.pgn.zst", game);
+        let session = t.session();
+        let report = session.run(ingest::sources(&t.cfg).unwrap()).unwrap();
+        assert_eq!((report.archives, report.games), (1, 1));
+
+        // A late event for the same archive, e.g. from a touch or a copy.
+        let path = PathBuf::from(&archive);
+        let mut pending = Pending::new(Duration::ZERO);
+        pending.add(path.clone());
+        assert!(pending.ready().is_empty());
+        assert_eq!(pending.ready(), [path]);
+        let report = session.run(vec![archive]).unwrap();
+        assert_eq!((report.archives, report.skipped, report.games), (0, 1, 0));
+    }
+}
@@ -1,64 +1,205 @@
-use crate::{chess_db, game_stats::GameWins, GameSummary};
+use crate::{chess_db::{self, Key}, game_stats::GameWins, GameSummary};
+use crate::bulk::RunWriter;
+use crate::dataset::{self, Datasets};
+use crate::dedup::SeenSet;
+use crate::hot::{HotBatch, HotTable};
+use crate::metrics;
+use crate::replay::{PrefixCache, Step};
+use ahash::RandomState;
+use anyhow::{Context, Result as AnyResult};
 use crossbeam_channel::Receiver;
 use rocksdb::{WriteBatch, WriteOptions, DB};
-use shakmaty::{Chess, Color, Move, Position};
-use std::collections::HashMap;
+use shakmaty::{Chess, Color, Position};
+use std::collections::{hash_map::Entry, HashMap};
+use std::mem::size_of;
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+/// Approximate heap cost of one map entry: key, value and hashbrown's
+/// control byte plus load‑factor slack.
+pub const ENTRY_BYTES: usize = size_of::<Key>() + size_of::<GameWins>() + 16;
+
+/// Hot keys collected locally before taking the shared table's locks.
+const HOT_BATCH: usize = 4096;
+
+/// Per‑worker cache sizes, see `config::Ingest` and `Budget`.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    pub prefix_plies: usize,
+    /// Steps the prefix trie holds, `STEP_BYTES` each.
+    pub prefix_capacity: usize,
+    pub flush_threshold: usize,
+    pub byte_limit: usize,
+}
 
 /// Per‑thread aggregation map.
 pub struct StatsCache {
-    map: HashMap<Vec<u8>, GameWins>,
+    map: HashMap<Key, GameWins, RandomState>,
     flush_threshold: usize,
 }
 
 impl StatsCache {
-    #[must_use] pub fn new(flush_threshold: usize) -> Self { Self { map: HashMap::new(), flush_threshold } }
+    /// Flushes once it holds `flush_threshold` entries or roughly
+    /// `byte_limit` bytes, whichever comes first.
+    #[must_use] pub fn new(flush_threshold: usize, byte_limit: usize) -> Self {
+        let flush_threshold = flush_threshold.min(byte_limit / ENTRY_BYTES).max(1);
+        Self { map: HashMap::default(), flush_threshold }
+    }
 
-    #[inline] fn bump(&mut self, key: Vec<u8>, wins: &GameWins) { let e = self.map.entry(key).or_default(); *e = e.combine(wins); }
+    #[inline] fn bump(&mut self, key: Key, wins: &GameWins) {
+        match self.map.entry(key) {
+            Entry::Occupied(mut e) => { let v = e.get_mut(); *v = v.combine(wins); }
+            Entry::Vacant(e) => { e.insert(*wins); }
+        }
+    }
     #[inline] fn should_flush(&self) -> bool { self.map.len() >= self.flush_threshold }
 
-    pub fn flush_to_db(&mut self, db: &DB) {
-        if self.map.is_empty() { return; }
-        let mut batch = WriteBatch::default();
-        for (k, v) in self.map.drain() { batch.merge(&k, v.to_bytes()); }
-        let mut opts = WriteOptions::default();
-        opts.disable_wal(true);
-        db.write_opt(batch, &opts).expect("rocksdb write failed");
+    /// Returns the number of totals written.
+    pub fn flush(&mut self, sink: Sink) -> AnyResult<usize> {
+        if self.map.is_empty() { return Ok(0); }
+        sink.write(self.map.drain())
+    }
+}
+
+/// Where flushed totals go.
+#[derive(Clone, Copy)]
+pub enum Sink<'a> {
+    /// Merge operands written straight into the datasets' column families.
+    Db(&'a DB, &'a Datasets),
+    /// Sorted runs on disk, turned into SST files at the end of a bulk load.
+    Runs(&'a RunWriter),
+}
+
+impl Sink<'_> {
+    /// Returns the number of entries written: merge operands, or run
+    /// records in bulk mode.
+    pub fn write(self, entries: impl Iterator<Item = (Key, GameWins)>) -> AnyResult<usize> {
+        match self {
+            Sink::Db(db, datasets) => {
+                let cfs = datasets.cfs(db);
+                let mut batch = WriteBatch::default();
+                for (k, v) in entries {
+                    batch.merge_cf(cfs[usize::from(k.dataset())], k.as_bytes(), v.to_bytes());
+                }
+                let n = batch.len();
+                let mut opts = WriteOptions::default();
+                opts.disable_wal(true);
+                db.write_opt(batch, &opts).context("write merge operands")?;
+                Ok(n)
+            }
+            Sink::Runs(runs) => {
+                let entries: Vec<_> = entries.collect();
+                let n = entries.len();
+                runs.spill(entries).context("spill sorted run")?;
+                Ok(n)
+            }
+        }
+    }
+}
+
+/// Routes shallow keys to the shared `HotTable`, everything else to the
+/// worker's own `StatsCache`, once per dataset of the current game.
+struct Aggregator<'a> {
+    hot: &'a HotTable,
+    hot_batch: HotBatch,
+    cache: StatsCache,
+    datasets: u64,
+}
+
+impl Aggregator<'_> {
+    #[inline] fn bump(&mut self, ply: usize, key: Key, wins: &GameWins) {
+        for ds in dataset::indices(self.datasets) {
+            let key = key.in_dataset(ds);
+            if self.hot.is_hot(ply) {
+                let e = self.hot_batch.entry(key).or_default();
+                *e = e.combine(wins);
+            } else {
+                self.cache.bump(key, wins);
+            }
+        }
+    }
+
+    fn absorb_hot(&mut self) {
+        let cache = &mut self.cache;
+        self.hot.absorb(&mut self.hot_batch, |k, v| cache.bump(k, &v));
     }
 }
 
 /// Entry point: called from `ingest` for each Rayon worker thread.
-pub fn run(rx: &Receiver<GameSummary>, db: &Arc<DB>, flush_threshold: usize) {
-    let mut cache = StatsCache::new(flush_threshold);
+/// Returns the number of games processed; duplicates are counted by `seen`.
+/// Stops at the first read or write that fails.
+pub fn run(
+    rx: &Receiver<GameSummary>,
+    sink: Sink,
+    hot: &HotTable,
+    seen: &SeenSet,
+    limits: Limits,
+    metrics: &metrics::Ingest,
+) -> AnyResult<u64> {
+    let mut agg = Aggregator {
+        hot,
+        hot_batch: hot.batch(),
+        cache: StatsCache::new(limits.flush_threshold, limits.byte_limit),
+        datasets: 0,
+    };
+    let mut prefixes = PrefixCache::new(limits.prefix_plies, limits.prefix_capacity);
+    let mut games = 0;
     while let Ok(game) = rx.recv() {
-        process_game(&game, &mut cache);
-        if cache.should_flush() { cache.flush_to_db(db); }
+        agg.datasets = seen.insert(&game.fingerprint, game.datasets)?;
+        if agg.datasets == 0 {
+            metrics.duplicates.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        process_game(&game, &mut agg, &mut prefixes);
+        games += 1;
+        metrics.games.fetch_add(1, Ordering::Relaxed);
+        if agg.hot_batch.len() >= HOT_BATCH { agg.absorb_hot(); }
+        if agg.cache.should_flush() {
+            let n = agg.cache.flush(sink)?;
+            metrics.operands.fetch_add(n as u64, Ordering::Relaxed);
+            metrics.flushes.fetch_add(1, Ordering::Relaxed);
+        }
     }
-    cache.flush_to_db(db); // final flush
+    agg.absorb_hot();
+    // Final flush; the hot table is flushed by `ingest`.
+    let n = agg.cache.flush(sink)?;
+    metrics.operands.fetch_add(n as u64, Ordering::Relaxed);
+    Ok(games)
 }
 
-fn process_game(game: &GameSummary, cache: &mut StatsCache) {
-    let mut board = Chess::new();
+fn process_game(game: &GameSummary, agg: &mut Aggregator, prefixes: &mut PrefixCache) {
     let wins = winner_to_wins(game.winner);
-    for san_plus in &game.sans {
-        accumulate_position(&board, &wins, cache);
+
+    // Jump over the deepest opening prefix replayed recently.
+    let mut parent = prefixes.lookup(&game.sans);
+    let (mut board, start) = match &parent {
+        Some(step) => {
+            for s in step.path() {
+                agg.bump(s.ply, s.pos_key, &wins);
+                agg.bump(s.ply, s.move_key, &wins);
+            }
+            (step.board.clone(), step.ply + 1)
+        }
+        None => (Chess::new(), 0),
+    };
+
+    for (ply, san_plus) in game.sans.iter().enumerate().skip(start) {
+        let keyable = chess_db::pos_to_keyable(&board);
+        let pos_key = chess_db::pos_key(&keyable);
+        agg.bump(ply, pos_key, &wins);
         let Ok(mv) = san_plus.san.to_move(&board) else { return };
-        accumulate_position_move(&board, &mv, &wins, cache);
+        let move_key = chess_db::pos_move_key(&keyable, &mv);
+        agg.bump(ply, move_key, &wins);
         board.play_unchecked(&mv);
-    }
-    accumulate_position(&board, &wins, cache); // final position
-}
-
-#[inline]
-fn accumulate_position(pos: &Chess, wins: &GameWins, cache: &mut StatsCache) {
-    let keyable = chess_db::pos_to_keyable(pos);
-    cache.bump(chess_db::pos_to_key(&keyable), wins);
-}
 
-#[inline]
-fn accumulate_position_move(pos: &Chess, mv: &Move, wins: &GameWins, cache: &mut StatsCache) {
-    let keyable = chess_db::pos_to_keyable(pos);
-    cache.bump(chess_db::pos_move_to_key(&keyable, mv), wins);
+        if prefixes.wants(ply) {
+            let step = Arc::new(Step::new(pos_key, move_key, board.clone(), ply, parent.take()));
+            prefixes.insert(step.clone());
+            parent = Some(step);
+        }
+    }
+    let keyable = chess_db::pos_to_keyable(&board);
+    agg.bump(game.sans.len(), chess_db::pos_key(&keyable), &wins); // final position
 }
 
 #[inline]